@@ -0,0 +1,60 @@
+//! Compares encoding into a growing buffer with `encode_to_bytes`, which allocates once.
+//!
+//! Run with `cargo bench --bench encode`.
+
+use std::hint::black_box;
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use itre::{Emo, Message};
+use itre::consts;
+use itre::encoder::Encoder;
+
+// Many small messages, spanning several compound slices
+fn wide(len: usize) -> Message {
+    Message::Compound((0..len).map(|i| match i % 3 {
+        0 => Message::Text(format!("message number {}", i)),
+        1 => Message::Emo(Emo::Laugh),
+        _ => Message::Image(consts::MESSAGE_IMAGE_FORMAT_PNG, vec![0x89; 64]),
+    }).collect())
+}
+
+// Compounds nested in compounds, with large payloads at the leaves
+fn deep(depth: usize) -> Message {
+    if depth == 0 {
+        return Message::Compound(vec![
+            Message::Text("长".repeat(30000)),
+            Message::Image(consts::MESSAGE_IMAGE_FORMAT_JPEG, vec![0xd8; 100_000]),
+        ]);
+    }
+    Message::Compound(vec![deep(depth - 1), deep(depth - 1)])
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let cases = vec![
+        ("wide", 1000, wide(1000)),
+        ("wide", 100_000, wide(100_000)),
+        ("deep", 6, deep(6)),
+    ];
+    let mut group = c.benchmark_group("encode");
+    for (name, size, msg) in &cases {
+        let id = format!("{}/{}", name, size);
+        group.throughput(Throughput::Bytes(msg.encoded_len() as u64));
+        group.bench_with_input(BenchmarkId::new("encode_into", &id), msg, |b, msg| {
+            b.iter(|| {
+                let mut buf = BytesMut::new();
+                msg.encode_into(&mut buf);
+                black_box(buf)
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("encode_to_bytes", &id), msg, |b, msg| {
+            b.iter(|| black_box(msg.encode_to_bytes()))
+        });
+        group.bench_with_input(BenchmarkId::new("encoded_len", &id), msg, |b, msg| {
+            b.iter(|| black_box(msg.encoded_len()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_encode);
+criterion_main!(benches);
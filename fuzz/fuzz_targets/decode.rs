@@ -0,0 +1,36 @@
+//! Decodes arbitrary bytes as a message and as an envelope. Whatever decodes must decode
+//! the same after being encoded again, and `MessageRef` must agree with `Message`.
+#![no_main]
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use itre::{Envelope, Message};
+use itre::decoder::{Decoder, DecodeOptions};
+use itre::encoder::Encoder;
+use itre::view::MessageRef;
+
+fn check<T: Decoder + Encoder + PartialEq + std::fmt::Debug>(data: &[u8], opts: &DecodeOptions) {
+    let mut buf = BytesMut::from(data);
+    if let Ok(value) = T::decode_with(&mut buf, opts) {
+        let mut encoded = BytesMut::new();
+        value.encode_into(&mut encoded);
+        assert_eq!(T::decode_with(&mut encoded, opts).unwrap(), value);
+        assert!(encoded.is_empty());
+    }
+}
+
+fn check_view(data: &[u8], opts: &DecodeOptions) {
+    let expected = Message::decode_with(&mut BytesMut::from(data), opts).map_err(|e| e.to_string());
+    let decoded = MessageRef::decode_with(&mut &data[..], opts)
+        .map(MessageRef::into_message)
+        .map_err(|e| e.to_string());
+    assert_eq!(decoded, expected);
+}
+
+fuzz_target!(|data: &[u8]| {
+    let lossy = DecodeOptions { lossy_utf8: true, ..DecodeOptions::default() };
+    for opts in &[DecodeOptions::default(), lossy] {
+        check::<Message>(data, opts);
+        check::<Envelope>(data, opts);
+        check_view(data, opts);
+    }
+});
@@ -0,0 +1,56 @@
+//! Feeds arbitrary bytes to a `StreamDecoder` in chunks. It must decode the same messages
+//! as `Message::decode_from`, stopping at the same error if any.
+#![no_main]
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use itre::Message;
+use itre::decoder::Decoder;
+use itre::stream::StreamDecoder;
+
+fuzz_target!(|data: &[u8]| {
+    let (chunk_size, data) = match data.split_first() {
+        Some((&size, data)) => (size as usize + 1, data),
+        None => return
+    };
+
+    let mut expected = Vec::new();
+    let mut buf = BytesMut::from(data);
+    let mut failed = false;
+    while !buf.is_empty() {
+        match Message::decode_from(&mut buf) {
+            Ok(msg) => expected.push(msg),
+            Err(_) => {
+                failed = true;
+                break;
+            }
+        }
+    }
+
+    let mut decoder = StreamDecoder::new();
+    let mut buf = BytesMut::new();
+    let mut decoded = Vec::new();
+    let mut stream_failed = false;
+    'feed: for chunk in data.chunks(chunk_size) {
+        buf.extend_from_slice(chunk);
+        loop {
+            match decoder.decode(&mut buf) {
+                Ok(Some(msg)) => decoded.push(msg),
+                Ok(None) => break,
+                Err(_) => {
+                    stream_failed = true;
+                    break 'feed;
+                }
+            }
+        }
+    }
+
+    if failed {
+        // A truncated message fails `decode_from` but just leaves the stream waiting
+        assert!(stream_failed || !buf.is_empty());
+    } else {
+        assert!(!stream_failed && buf.is_empty());
+    }
+    let len = decoded.len().min(expected.len());
+    assert_eq!(decoded[..len], expected[..len]);
+    assert_eq!(decoded.len(), expected.len());
+});
@@ -0,0 +1,321 @@
+//! `#[derive(Encoder, Decoder)]` for ITRE.
+//!
+//! Use through the `derive` feature of the `itre` crate, which re-exports both macros.
+//!
+//! Structs encode their fields in declaration order using the fields' own `Encoder` impls,
+//! with nothing in between. Enums encode a 1-byte code identifying the variant, followed by
+//! the variant's fields. A variant's code is its index unless overridden. Fields are decoded
+//! with `Decoder::decode_nested`, so messages in them stay within the `DecodeLimits` of the
+//! message the type is nested in.
+//!
+//! Attributes:
+//!   `#[itre(code = N)]` on a struct or enum: encode as a message with type code `N`, wrapped
+//!   in the length-delimited envelope like every message added after the first version:
+//!   `|N|Payload length (4 bytes)|Encoding as above|`. `N` must be an extension code, so
+//!   decoders that do not know the type skip it as `Message::Unknown`. Decoding fails with
+//!   `Error::InvalidTypeCode` if a different code is found, and ignores bytes following the
+//!   fields in the payload. Types used as `registry::ExtensionType` get their envelope from
+//!   `Message::Object` and should not have a code, or it is written twice.
+//!   `#[itre(code = N)]` on an enum variant: use `N` as the variant code.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, parse_quote, Attribute, Data, DeriveInput, Error, Fields,
+          GenericParam, Generics, Ident, LitInt, Result};
+
+#[proc_macro_derive(Encoder, attributes(itre))]
+pub fn derive_encoder(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_encoder(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+#[proc_macro_derive(Decoder, attributes(itre))]
+pub fn derive_decoder(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_decoder(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+// Codes in the extension range taken by built-in messages. This crate cannot depend on
+// `itre`, so keep in sync with `itre::consts::BUILTIN_EXTENSION_RANGE_TYPE_CODES`.
+const BUILTIN_EXTENSION_RANGE_TYPE_CODES: &[u8] = &[129, 130, 131, 132, 133, 140, 141];
+
+// Reads the `N` of `#[itre(code = N)]` on a struct or enum, if present, which must be
+// an extension code.
+fn type_code(attrs: &[Attribute]) -> Result<Option<u8>> {
+    let lit = match code_attr(attrs)? {
+        Some(lit) => lit,
+        None => return Ok(None)
+    };
+    let code = lit.base10_parse::<u8>()?;
+    if !(129..=249).contains(&code) || BUILTIN_EXTENSION_RANGE_TYPE_CODES.contains(&code) {
+        let message = format!("type code {} is not an extension code, \
+                               expected 134 ~ 139 or 142 ~ 249", code);
+        return Err(Error::new_spanned(lit, message));
+    }
+    Ok(Some(code))
+}
+
+// Reads the `N` of `#[itre(code = N)]`, if present.
+fn code_attr(attrs: &[Attribute]) -> Result<Option<LitInt>> {
+    let mut code = None;
+    for attr in attrs {
+        if !attr.path().is_ident("itre") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("code") {
+                let lit: LitInt = meta.value()?.parse()?;
+                lit.base10_parse::<u8>()?;
+                code = Some(lit);
+                Ok(())
+            } else {
+                Err(meta.error("unsupported itre attribute, expected `code = N`"))
+            }
+        })?;
+    }
+    Ok(code)
+}
+
+// Codes of all variants of an enum, in declaration order.
+fn variant_codes(data: &syn::DataEnum) -> Result<Vec<u8>> {
+    let mut codes: Vec<u8> = Vec::new();
+    for (index, variant) in data.variants.iter().enumerate() {
+        let code = match code_attr(&variant.attrs)? {
+            Some(lit) => lit.base10_parse::<u8>()?,
+            None if index <= u8::MAX as usize => index as u8,
+            None => return Err(Error::new_spanned(variant, "too many variants for 1-byte codes"))
+        };
+        if codes.contains(&code) {
+            return Err(Error::new_spanned(variant, format!("duplicate variant code {}", code)));
+        }
+        codes.push(code);
+    }
+    Ok(codes)
+}
+
+fn add_bounds(mut generics: Generics, bound: syn::TypeParamBound) -> Generics {
+    for param in &mut generics.params {
+        if let GenericParam::Type(ref mut param) = *param {
+            param.bounds.push(bound.clone());
+        }
+    }
+    generics
+}
+
+// Names to bind the fields to when destructuring.
+fn bindings(fields: &Fields) -> Vec<Ident> {
+    fields.iter().enumerate().map(|(i, field)| match field.ident {
+        Some(ref ident) => ident.clone(),
+        None => format_ident!("__field{}", i)
+    }).collect()
+}
+
+// Pattern (or constructor) for `path` with the fields bound to `names`.
+fn destructure(path: TokenStream2, fields: &Fields, names: &[Ident]) -> TokenStream2 {
+    match *fields {
+        Fields::Named(_) => quote!(#path { #(#names),* }),
+        Fields::Unnamed(_) => quote!(#path ( #(#names),* )),
+        Fields::Unit => path
+    }
+}
+
+// Writes the type code and the envelope header, given the expression `len` computing the
+// payload length.
+fn encode_code(code: Option<u8>, len: &TokenStream2) -> TokenStream2 {
+    match code {
+        Some(code) => quote! {
+            ::itre::encoder::Encoder::encode_into(&#code, buf);
+            let __len = <u32 as ::std::convert::TryFrom<usize>>::try_from({ #len })
+                .expect("payload does not fit in a 4-byte length prefix");
+            ::itre::encoder::Encoder::encode_into(&__len, buf);
+        },
+        None => quote!()
+    }
+}
+
+// Type code and payload length
+fn code_len(code: Option<u8>) -> usize {
+    if code.is_some() { 1 + 4 } else { 0 }
+}
+
+// Reads and checks the type code and the envelope header, then shadows `buf` and `ctx` with
+// the payload.
+fn decode_code(code: Option<u8>) -> TokenStream2 {
+    match code {
+        Some(code) => quote! {
+            let __code = <u8 as ::itre::decoder::Decoder>::decode_with(buf, opts)?;
+            if __code != #code {
+                return Err(::itre::error::Error::InvalidTypeCode(__code));
+            }
+            let __len = <u32 as ::itre::decoder::Decoder>::decode_with(buf, opts)? as usize;
+            if __len > opts.limits.max_total_bytes {
+                return Err(::itre::error::Error::LimitExceeded(::itre::error::Limit::TotalBytes));
+            }
+            if buf.len() < __len {
+                return Err(::itre::error::Error::UnexpectedEof(__len - buf.len()));
+            }
+            let mut __payload = buf.split_to(__len);
+            let ctx = &ctx.payload(buf);
+            let buf = &mut __payload;
+        },
+        None => quote!()
+    }
+}
+
+fn expand_encoder(input: DeriveInput) -> Result<TokenStream2> {
+    let name = &input.ident;
+    let code = type_code(&input.attrs)?;
+    let (body, len) = match input.data {
+        Data::Struct(ref data) => {
+            let names = bindings(&data.fields);
+            let pattern = destructure(quote!(#name), &data.fields, &names);
+            let body = quote! {
+                let #pattern = self;
+                #(::itre::encoder::Encoder::encode_into(#names, buf);)*
+            };
+            let len = quote! {
+                let #pattern = self;
+                0 #(+ ::itre::encoder::Encoder::encoded_len(#names))*
+            };
+            (body, len)
+        },
+        Data::Enum(ref data) => {
+            let codes = variant_codes(data)?;
+            let arms = data.variants.iter().zip(codes).map(|(variant, code)| {
+                let ident = &variant.ident;
+                let names = bindings(&variant.fields);
+                let pattern = destructure(quote!(#name::#ident), &variant.fields, &names);
+                quote! {
+                    #pattern => {
+                        ::itre::encoder::Encoder::encode_into(&#code, buf);
+                        #(::itre::encoder::Encoder::encode_into(#names, buf);)*
+                    }
+                }
+            });
+            let len_arms = data.variants.iter().map(|variant| {
+                let ident = &variant.ident;
+                let names = bindings(&variant.fields);
+                let pattern = destructure(quote!(#name::#ident), &variant.fields, &names);
+                quote! {
+                    #pattern => 1 #(+ ::itre::encoder::Encoder::encoded_len(#names))*,
+                }
+            });
+            let body = quote! {
+                match self {
+                    #(#arms)*
+                }
+            };
+            // Variant code
+            let len = quote! {
+                match self {
+                    #(#len_arms)*
+                }
+            };
+            (body, len)
+        },
+        Data::Union(_) => return Err(Error::new(Span::call_site(), "unions are not supported"))
+    };
+    let prefix = encode_code(code, &len);
+    let prefix_len = code_len(code);
+    let generics = add_bounds(input.generics.clone(), parse_quote!(::itre::encoder::Encoder));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::itre::encoder::Encoder for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn encode_into(&self, buf: &mut ::itre::__private::BytesMut) {
+                #prefix
+                #body
+            }
+
+            #[allow(unused_variables)]
+            fn encoded_len(&self) -> usize {
+                #prefix_len + { #len }
+            }
+        }
+    })
+}
+
+fn expand_decoder(input: DeriveInput) -> Result<TokenStream2> {
+    let name = &input.ident;
+    let prefix = decode_code(type_code(&input.attrs)?);
+    let decode_field = quote!(::itre::decoder::Decoder::decode_nested(buf, ctx)?);
+    let body = match input.data {
+        Data::Struct(ref data) => {
+            let names = bindings(&data.fields);
+            let value = destructure(quote!(#name), &data.fields, &names);
+            quote! {
+                #(let #names = #decode_field;)*
+                Ok(#value)
+            }
+        },
+        Data::Enum(ref data) => {
+            let codes = variant_codes(data)?;
+            let arms = data.variants.iter().zip(codes).map(|(variant, code)| {
+                let ident = &variant.ident;
+                let names = bindings(&variant.fields);
+                let value = destructure(quote!(#name::#ident), &variant.fields, &names);
+                quote! {
+                    #code => {
+                        #(let #names = #decode_field;)*
+                        Ok(#value)
+                    }
+                }
+            });
+            quote! {
+                let __code = <u8 as ::itre::decoder::Decoder>::decode_with(buf, opts)?;
+                match __code {
+                    #(#arms)*
+                    _ => Err(::itre::error::Error::InvalidTypeCode(__code))
+                }
+            }
+        },
+        Data::Union(_) => return Err(Error::new(Span::call_site(), "unions are not supported"))
+    };
+    let generics = add_bounds(input.generics.clone(), parse_quote!(::itre::decoder::Decoder));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::itre::decoder::Decoder for #name #ty_generics #where_clause {
+            fn decode_with(buf: &mut ::itre::__private::BytesMut,
+                           opts: &::itre::decoder::DecodeOptions)
+                           -> ::itre::decoder::Result<Self> {
+                Self::decode_nested(buf, &::itre::decoder::DecodeContext::new(opts, buf))
+            }
+
+            #[allow(unused_variables)]
+            fn decode_nested(buf: &mut ::itre::__private::BytesMut,
+                             ctx: &::itre::decoder::DecodeContext)
+                             -> ::itre::decoder::Result<Self> {
+                let opts = ctx.opts();
+                #prefix
+                #body
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use syn::{parse_quote, DeriveInput};
+    use super::type_code;
+
+    #[test]
+    fn type_code_range() {
+        let input: DeriveInput = parse_quote! {
+            #[itre(code = 142)]
+            struct Sticker;
+        };
+        assert_eq!(type_code(&input.attrs).unwrap(), Some(142));
+        // Outside of the extension range, or taken by a built-in message
+        for code in &[0u8, 1, 128, 129, 140, 250, 255] {
+            let input: DeriveInput = parse_quote! {
+                #[itre(code = #code)]
+                struct Sticker;
+            };
+            let e = type_code(&input.attrs).unwrap_err();
+            assert!(e.to_string().starts_with(&format!("type code {} is not", code)), "{}", e);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::sync::Arc;
+use bytes::BytesMut;
+use itre::{Decoder, Encoder, Emo, Message};
+use itre::decoder::Decoder as _;
+use itre::encoder::Encoder as _;
+use itre::decoder::DecodeOptions;
+use itre::error::{Error, Limit};
+use itre::registry::{ExtensionType, Registry};
+
+#[derive(Debug, PartialEq, Encoder, Decoder)]
+#[itre(code = 143)]
+struct Sticker {
+    pack: String,
+    index: u16,
+    animated: bool,
+}
+
+#[derive(Debug, PartialEq, Encoder, Decoder)]
+struct Pair(u8, i32);
+
+#[derive(Debug, PartialEq, Encoder, Decoder)]
+struct Marker;
+
+#[derive(Debug, PartialEq, Encoder, Decoder)]
+enum Event {
+    Joined(u32),
+    #[itre(code = 10)]
+    Left { user: u32, reason: Option<String> },
+    Ping,
+    Sent(Message),
+    Reacted(Emo),
+}
+
+#[derive(Debug, PartialEq, Encoder, Decoder)]
+#[itre(code = 142)]
+struct Batch<T> {
+    items: Vec<T>,
+}
+
+// Registered, so a message can quote another one holding a quote, and so on
+#[derive(Debug, PartialEq, Encoder, Decoder)]
+struct Quote {
+    events: Vec<Event>,
+}
+
+impl ExtensionType for Quote {
+    const CODE: u8 = 144;
+}
+
+fn roundtrip<T: itre::encoder::Encoder + itre::decoder::Decoder>(value: &T) -> (BytesMut, T) {
+    let mut buf = BytesMut::new();
+    value.encode_into(&mut buf);
+    assert_eq!(value.encoded_len(), buf.len());
+    let encoded = buf.clone();
+    let decoded = T::decode_from(&mut buf).unwrap();
+    assert!(buf.is_empty());
+    (encoded, decoded)
+}
+
+#[test]
+fn struct_with_type_code() {
+    let sticker = Sticker { pack: String::from("cats"), index: 3, animated: true };
+    let (encoded, decoded) = roundtrip(&sticker);
+    assert_eq!(&encoded[..], b"\x8f\x00\x00\x00\x09\x00\x04cats\x00\x03\x01");
+    assert_eq!(decoded, sticker);
+}
+
+#[test]
+fn struct_with_type_code_in_stream() {
+    // Peers that do not know the type skip it like any unknown message
+    let sticker = Sticker { pack: String::from("cats"), index: 3, animated: true };
+    let mut buf = BytesMut::new();
+    sticker.encode_into(&mut buf);
+    Message::Nop.encode_into(&mut buf);
+    match Message::decode_from(&mut buf).unwrap() {
+        Message::Unknown(0x8f, ref payload) => assert_eq!(payload.len(), 9),
+        other => panic!("unexpected message: {:?}", other)
+    }
+    assert_eq!(Message::decode_from(&mut buf).unwrap(), Message::Nop);
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn struct_without_type_code() {
+    let (encoded, decoded) = roundtrip(&Pair(1, -1));
+    assert_eq!(&encoded[..], b"\x01\xff\xff\xff\xff");
+    assert_eq!(decoded, Pair(1, -1));
+
+    let (encoded, decoded) = roundtrip(&Marker);
+    assert!(encoded.is_empty());
+    assert_eq!(decoded, Marker);
+    // Zero-size elements take up no input, so the input would not bound their count
+    // Zero-size elements would let a short input announce billions of them
+    let mut buf = BytesMut::from(&b"\x00\x00\x00\x02\x00\x00"[..]);
+    match Vec::<Marker>::decode_from(&mut buf) {
+        Err(Error::EmptyElement) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+}
+
+#[test]
+fn enum_variant_codes() {
+    let (encoded, _) = roundtrip(&Event::Joined(7));
+    assert_eq!(&encoded[..], b"\x00\x00\x00\x00\x07");
+    let (encoded, _) = roundtrip(&Event::Left { user: 7, reason: None });
+    assert_eq!(&encoded[..], b"\x0a\x00\x00\x00\x07\x00");
+    let (encoded, _) = roundtrip(&Event::Ping);
+    assert_eq!(&encoded[..], b"\x02");
+
+    for event in &[
+        Event::Left { user: 1, reason: Some(String::from("bye")) },
+        Event::Sent(Message::Compound(vec![Message::Text(String::from("hi")), Message::Nop])),
+        Event::Reacted(Emo::Laugh),
+    ] {
+        assert_eq!(&roundtrip(event).1, event);
+    }
+}
+
+#[test]
+fn generic_struct() {
+    let batch = Batch { items: vec![Pair(1, 2), Pair(3, 4)] };
+    assert_eq!(roundtrip(&batch).1, batch);
+}
+
+#[test]
+fn invalid_codes() {
+    let mut buf = BytesMut::from(&b"\x8c\x00\x00"[..]);
+    match Sticker::decode_from(&mut buf) {
+        Err(Error::InvalidTypeCode(0x8c)) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+    let mut buf = BytesMut::from(&b"\x01"[..]);
+    match Event::decode_from(&mut buf) {
+        Err(Error::InvalidTypeCode(0x01)) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+}
+
+#[test]
+fn nested_in_registered_type() {
+    let mut registry = Registry::new();
+    registry.register_type::<Quote>().unwrap();
+    let opts = DecodeOptions { registry: Some(Arc::new(registry)), ..DecodeOptions::default() };
+
+    let mut msg = Message::Nop;
+    for _ in 0..opts.limits.max_depth {
+        msg = Message::Object(Box::new(Quote { events: vec![Event::Ping, Event::Sent(msg)] }));
+    }
+    let mut buf = BytesMut::new();
+    msg.encode_into(&mut buf);
+    assert_eq!(Message::decode_with(&mut buf.clone(), &opts).unwrap(), msg);
+
+    // The depth is counted across the fields of registered types
+    let msg = Message::Object(Box::new(Quote { events: vec![Event::Sent(msg)] }));
+    let mut buf = BytesMut::new();
+    msg.encode_into(&mut buf);
+    match Message::decode_with(&mut buf, &opts).map_err(Error::into_kind) {
+        Err(Error::LimitExceeded(Limit::Depth)) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+}
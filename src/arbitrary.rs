@@ -0,0 +1,270 @@
+//! `proptest` strategies generating valid messages, i.e. messages that decode back into
+//! themselves after being encoded.
+//!
+//! Enabled by the `proptest` feature. `Message::Extension` and `Message::Object` are never
+//! generated since they only decode with a registry; `Message::Unknown` stands in for them.
+
+use proptest::prelude::*;
+use proptest::collection::vec;
+use proptest::option;
+use proptest::strategy::LazyJust;
+use super::consts;
+use super::{Message, Control, Emo, Envelope, Span, Style, Attachment, Content, Chunk, Location};
+
+/// Texts, now and then longer than a single slice.
+pub fn text() -> BoxedStrategy<String> {
+    prop_oneof![
+        15 => ".{0,32}",
+        1 => (".{0,4}", any::<char>(),
+               consts::TEXT_SLICE_MAX_LENGTH_S - 8..2 * consts::TEXT_SLICE_MAX_LENGTH_S)
+            .prop_map(|(prefix, c, len)| prefix + &c.to_string().repeat(len / c.len_utf8())),
+    ].boxed()
+}
+
+fn bytes() -> impl Strategy<Value = Vec<u8>> {
+    vec(any::<u8>(), 0..64)
+}
+
+fn image_format() -> impl Strategy<Value = u8> {
+    consts::MESSAGE_IMAGE_FORMAT_PNG..=consts::MESSAGE_IMAGE_FORMAT_WEBP
+}
+
+// Type codes that decode into `Message::Unknown` without a registry
+fn unknown_type_code() -> impl Strategy<Value = u8> {
+    any::<u8>().prop_filter("built-in type code", |&code| !consts::is_builtin_type_code(code))
+}
+
+impl Arbitrary for Control {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Control>;
+
+    fn arbitrary_with(_: ()) -> BoxedStrategy<Control> {
+        prop_oneof![
+            LazyJust::new(|| Control::Ping),
+            LazyJust::new(|| Control::Pong),
+            any::<u64>().prop_map(Control::Ack),
+            LazyJust::new(|| Control::TypingStart),
+            LazyJust::new(|| Control::TypingStop),
+            any::<u64>().prop_map(Control::ReadReceipt),
+            any::<u16>().prop_map(Control::Close),
+            (any::<u16>(), text()).prop_map(|(code, description)| Control::Error { code, description }),
+        ].boxed()
+    }
+}
+
+impl Arbitrary for Emo {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Emo>;
+
+    fn arbitrary_with(_: ()) -> BoxedStrategy<Emo> {
+        prop_oneof![
+            LazyJust::new(|| Emo::Nop),
+            LazyJust::new(|| Emo::Laugh),
+            LazyJust::new(|| Emo::Cry),
+            (".{0,32}", option::of((image_format(), bytes())))
+                .prop_map(|(id, image)| Emo::Custom(id, image)),
+        ].boxed()
+    }
+}
+
+impl Arbitrary for Style {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Style>;
+
+    fn arbitrary_with(_: ()) -> BoxedStrategy<Style> {
+        prop_oneof![
+            Just(Style::Bold),
+            Just(Style::Italic),
+            Just(Style::Code),
+            Just(Style::Strikethrough),
+            ".{0,32}".prop_map(Style::Link),
+            any::<u64>().prop_map(Style::Mention),
+        ].boxed()
+    }
+}
+
+/// Rich texts whose spans fall on character boundaries.
+pub fn rich_text() -> BoxedStrategy<(String, Vec<Span>)> {
+    text().prop_flat_map(|text| {
+        let boundaries: Vec<u32> = text.char_indices().map(|(i, _)| i as u32)
+            .chain(Some(text.len() as u32))
+            .collect();
+        let span = (proptest::sample::select(boundaries.clone()),
+                    proptest::sample::select(boundaries),
+                    any::<Style>())
+            .prop_map(|(a, b, style)| Span { start: a.min(b), end: a.max(b), style });
+        (Just(text), vec(span, 0..8))
+    }).boxed()
+}
+
+impl Arbitrary for Attachment {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Attachment>;
+
+    fn arbitrary_with(_: ()) -> BoxedStrategy<Attachment> {
+        let content = prop_oneof![
+            bytes().prop_map(Content::Inline),
+            any::<(u64, u32)>().prop_map(|(transfer, count)| Content::Chunked { transfer, count }),
+        ];
+        (".{0,32}", ".{0,32}", any::<u64>(), any::<[u8; consts::SHA256_LENGTH]>(), content)
+            .prop_map(|(filename, mime_type, size, sha256, content)| Attachment {
+                filename, mime_type, size, sha256, content
+            }).boxed()
+    }
+}
+
+impl Arbitrary for Chunk {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Chunk>;
+
+    fn arbitrary_with(_: ()) -> BoxedStrategy<Chunk> {
+        (any::<u64>(), any::<u32>(), bytes())
+            .prop_map(|(transfer, index, data)| Chunk { transfer, index, data })
+            .boxed()
+    }
+}
+
+impl Arbitrary for Location {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Location>;
+
+    fn arbitrary_with(_: ()) -> BoxedStrategy<Location> {
+        (-consts::LATITUDE_MAX_MICRODEGREES..=consts::LATITUDE_MAX_MICRODEGREES,
+         -consts::LONGITUDE_MAX_MICRODEGREES..=consts::LONGITUDE_MAX_MICRODEGREES,
+         any::<Option<u32>>(),
+         option::of(".{0,32}"),
+         any::<Option<u64>>())
+            .prop_map(|(latitude, longitude, accuracy, name, live_until)| Location {
+                latitude, longitude, accuracy, name, live_until
+            }).boxed()
+    }
+}
+
+// Messages other than compounds
+fn leaf() -> BoxedStrategy<Message> {
+    prop_oneof![
+        LazyJust::new(|| Message::Nop),
+        any::<Control>().prop_map(Message::Control),
+        text().prop_map(Message::Text),
+        rich_text().prop_map(|(text, spans)| Message::RichText(text, spans)),
+        any::<Emo>().prop_map(Message::Emo),
+        any::<Attachment>().prop_map(Message::Attachment),
+        any::<Chunk>().prop_map(Message::Chunk),
+        any::<Location>().prop_map(Message::Location),
+        (image_format(), bytes()).prop_map(|(format, data)| Message::Image(format, data)),
+        (consts::MESSAGE_VOICE_CODEC_OPUS..=consts::MESSAGE_VOICE_CODEC_AAC,
+         any::<u32>(), option::of(bytes()), bytes())
+            .prop_map(|(codec, duration, waveform, data)| Message::Voice {
+                codec, duration, waveform, data
+            }),
+        bytes().prop_map(Message::Binary),
+        (unknown_type_code(), bytes()).prop_map(|(code, payload)| Message::Unknown(code, payload)),
+    ].boxed()
+}
+
+// Cheap messages filling the compounds longer than a single slice
+fn small_leaf() -> BoxedStrategy<Message> {
+    prop_oneof![
+        LazyJust::new(|| Message::Nop),
+        "[a-z]{0,8}".prop_map(Message::Text),
+        any::<u64>().prop_map(|id| Message::Control(Control::Ack(id))),
+    ].boxed()
+}
+
+/// Message trees up to `depth` compounds deep, with compounds now and then longer than
+/// a single slice.
+pub fn message(depth: u32) -> BoxedStrategy<Message> {
+    leaf().prop_recursive(depth, 256, 8, |inner| prop_oneof![
+        8 => vec(inner, 0..8).prop_map(Message::Compound),
+        1 => vec(small_leaf(), consts::COMPOUND_SLICE_MAX_LENGTH_S - 4..600).prop_map(Message::Compound),
+    ]).boxed()
+}
+
+impl Arbitrary for Message {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Message>;
+
+    fn arbitrary_with(_: ()) -> BoxedStrategy<Message> {
+        message(4)
+    }
+}
+
+impl Arbitrary for Envelope {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Envelope>;
+
+    fn arbitrary_with(_: ()) -> BoxedStrategy<Envelope> {
+        (any::<(u64, u64, u64, u64)>(), any::<Option<u64>>(), any::<Message>())
+            .prop_map(|((id, sender, conversation, timestamp), reply_to, message)| Envelope {
+                id, sender, conversation, timestamp, reply_to, message
+            }).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use proptest::prelude::*;
+    use super::super::{Message, Envelope};
+    use super::super::decoder::Decoder;
+    use super::super::encoder::Encoder;
+    use super::super::stream::StreamDecoder;
+
+    fn encode<T: Encoder>(value: &T) -> BytesMut {
+        let mut buf = BytesMut::new();
+        value.encode_into(&mut buf);
+        buf
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip_message(msg in any::<Message>()) {
+            let mut buf = encode(&msg);
+            prop_assert_eq!(Message::decode_from(&mut buf).unwrap(), msg);
+            prop_assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn roundtrip_envelope(envelope in any::<Envelope>()) {
+            let mut buf = encode(&envelope);
+            prop_assert_eq!(Envelope::decode_from(&mut buf).unwrap(), envelope);
+            prop_assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn roundtrip_stream(msgs in proptest::collection::vec(any::<Message>(), 1..4),
+                            chunk_size in 1..4096usize) {
+            let mut encoded = BytesMut::new();
+            for msg in &msgs {
+                msg.encode_into(&mut encoded);
+            }
+            let mut decoder = StreamDecoder::new();
+            let mut buf = BytesMut::new();
+            let mut decoded = Vec::new();
+            for chunk in encoded.chunks(chunk_size) {
+                buf.extend_from_slice(chunk);
+                while let Some(msg) = decoder.decode(&mut buf).unwrap() {
+                    decoded.push(msg);
+                }
+            }
+            prop_assert_eq!(decoded, msgs);
+            prop_assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn decode_corrupted(msg in any::<Message>(), pos in any::<usize>(), byte in any::<u8>(),
+                            truncate in any::<bool>()) {
+            let mut bytes = encode(&msg).to_vec();
+            let pos = pos % bytes.len();
+            if truncate {
+                bytes.truncate(pos);
+            } else {
+                bytes[pos] = byte;
+            }
+            let mut buf = BytesMut::from(&bytes[..]);
+            let _ = Message::decode_from(&mut buf);
+            let mut buf = BytesMut::from(&bytes[..]);
+            let _ = StreamDecoder::new().decode(&mut buf);
+        }
+    }
+}
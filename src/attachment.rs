@@ -0,0 +1,418 @@
+use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use super::consts;
+use super::{Attachment, Chunk, Content};
+use super::decoder::Result;
+use super::error::{Error, ReassemblyLimit};
+
+/// SHA-256 digest of `data`, as carried by `Attachment::sha256`.
+pub fn sha256(data: &[u8]) -> [u8; consts::SHA256_LENGTH] {
+    Sha256::digest(data).into()
+}
+
+impl Attachment {
+    /// An attachment carrying `data` inline.
+    pub fn inline(filename: String, mime_type: String, data: Vec<u8>) -> Attachment {
+        Attachment {
+            filename,
+            mime_type,
+            size: data.len() as u64,
+            sha256: sha256(&data),
+            content: Content::Inline(data),
+        }
+    }
+
+    /// An attachment announcing a chunked transfer of `data`, along with the chunks.
+    ///
+    /// Each chunk holds at most `chunk_size` bytes. Panics if `chunk_size` is 0, or if there
+    /// would be more than 2^32-1 chunks, which do not fit in the chunk count.
+    pub fn chunked(filename: String, mime_type: String, data: &[u8], transfer: u64,
+                   chunk_size: usize) -> (Attachment, Vec<Chunk>) {
+        let count = data.chunks(chunk_size).len();
+        assert!(count <= u32::MAX as usize, "{} chunks do not fit in a chunk count", count);
+        let chunks: Vec<Chunk> = data.chunks(chunk_size).enumerate().map(|(index, data)| {
+            Chunk { transfer, index: index as u32, data: data.to_vec() }
+        }).collect();
+        let attachment = Attachment {
+            filename,
+            mime_type,
+            size: data.len() as u64,
+            sha256: sha256(data),
+            content: Content::Chunked { transfer, count: count as u32 },
+        };
+        (attachment, chunks)
+    }
+}
+
+/// A complete attachment whose size and digest have been checked.
+#[derive(Debug, PartialEq)]
+pub struct File {
+    pub filename: String,
+    pub mime_type: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Transfer {
+    attachment: Option<Attachment>,
+    chunks: HashMap<u32, Vec<u8>>,
+    // Total length of `chunks`
+    received: u64,
+}
+
+/// Bounds on what a `Reassembler` keeps while transfers are incomplete.
+///
+/// Exceeding one fails with `Error::ReassemblyLimitExceeded`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReassemblyLimits {
+    /// Maximum number of incomplete transfers. Defaults to 64.
+    pub max_transfers: usize,
+    /// Maximum number of chunks of a transfer, both announced and received. Defaults to 65536.
+    pub max_chunks: usize,
+    /// Maximum number of bytes held in received chunks, over all transfers, and maximum
+    /// announced size of a chunked attachment. Defaults to 64 MiB.
+    pub max_pending_bytes: u64,
+}
+
+impl Default for ReassemblyLimits {
+    fn default() -> Self {
+        ReassemblyLimits {
+            max_transfers: 64,
+            max_chunks: 65536,
+            max_pending_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Collects attachments and their chunks into complete files.
+///
+/// Chunks may arrive before the attachment announcing them and in any order. A transfer
+/// that fails is forgotten, so later chunks of it start a new one. Transfers that are never
+/// completed are kept until they are cancelled, within the `ReassemblyLimits`.
+#[derive(Default)]
+pub struct Reassembler {
+    transfers: HashMap<u64, Transfer>,
+    limits: ReassemblyLimits,
+    // Total length of the chunks of all transfers
+    pending_bytes: u64,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler::default()
+    }
+
+    pub fn with_limits(limits: ReassemblyLimits) -> Reassembler {
+        Reassembler { limits, ..Reassembler::default() }
+    }
+
+    /// Number of transfers that are not complete yet.
+    pub fn pending(&self) -> usize {
+        self.transfers.len()
+    }
+
+    /// Forgets transfer `transfer` and the chunks received for it, e.g. when it has been
+    /// stale for too long. Returns whether the transfer was pending.
+    pub fn cancel(&mut self, transfer: u64) -> bool {
+        match self.transfers.remove(&transfer) {
+            Some(entry) => {
+                self.pending_bytes -= entry.received;
+                true
+            },
+            None => false
+        }
+    }
+
+    // Entry of transfer `transfer`, started if new and there is room for it.
+    fn entry(&mut self, transfer: u64) -> Result<&mut Transfer> {
+        if !self.transfers.contains_key(&transfer)
+            && self.transfers.len() >= self.limits.max_transfers {
+            return Err(Error::ReassemblyLimitExceeded(ReassemblyLimit::Transfers));
+        }
+        Ok(self.transfers.entry(transfer).or_default())
+    }
+
+    // Forgets transfer `transfer` and returns `error`.
+    fn fail<T>(&mut self, transfer: u64, error: Error) -> Result<T> {
+        self.cancel(transfer);
+        Err(error)
+    }
+
+    /// Returns the file if all of its content is available, which is always the case
+    /// for inline attachments.
+    ///
+    /// Announcing a transfer again replaces the earlier announcement.
+    pub fn add_attachment(&mut self, mut attachment: Attachment) -> Result<Option<File>> {
+        let (transfer, count) = match attachment.content {
+            Content::Inline(ref mut data) => {
+                let data = std::mem::take(data);
+                return finish(&attachment, data).map(Some);
+            },
+            Content::Chunked { transfer, count } => (transfer, count)
+        };
+        let limits = self.limits.clone();
+        let entry = self.entry(transfer)?;
+        if let Some(&index) = entry.chunks.keys().find(|&&index| index >= count) {
+            return self.fail(transfer, Error::InvalidChunk(index));
+        }
+        if entry.received > attachment.size {
+            let received = entry.received;
+            return self.fail(transfer, Error::AttachmentSizeMismatch(received));
+        }
+        if count as usize > limits.max_chunks {
+            return self.fail(transfer, Error::ReassemblyLimitExceeded(ReassemblyLimit::Chunks));
+        }
+        if attachment.size > limits.max_pending_bytes {
+            return self.fail(transfer, Error::ReassemblyLimitExceeded(ReassemblyLimit::Bytes));
+        }
+        entry.attachment = Some(attachment);
+        self.complete(transfer)
+    }
+
+    /// Returns the file if this was the last chunk missing.
+    pub fn add_chunk(&mut self, chunk: Chunk) -> Result<Option<File>> {
+        let limits = self.limits.clone();
+        let pending_bytes = self.pending_bytes;
+        let entry = self.entry(chunk.transfer)?;
+        let out_of_range = match entry.attachment {
+            Some(Attachment { content: Content::Chunked { count, .. }, .. }) => chunk.index >= count,
+            _ => false
+        };
+        if out_of_range || entry.chunks.contains_key(&chunk.index) {
+            return self.fail(chunk.transfer, Error::InvalidChunk(chunk.index));
+        }
+        if entry.chunks.len() >= limits.max_chunks {
+            return self.fail(chunk.transfer, Error::ReassemblyLimitExceeded(ReassemblyLimit::Chunks));
+        }
+        let len = chunk.data.len() as u64;
+        if pending_bytes + len > limits.max_pending_bytes {
+            return self.fail(chunk.transfer, Error::ReassemblyLimitExceeded(ReassemblyLimit::Bytes));
+        }
+        if let Some(ref attachment) = entry.attachment {
+            if entry.received + len > attachment.size {
+                let received = entry.received + len;
+                return self.fail(chunk.transfer, Error::AttachmentSizeMismatch(received));
+            }
+        }
+        entry.received += len;
+        entry.chunks.insert(chunk.index, chunk.data);
+        self.pending_bytes += len;
+        self.complete(chunk.transfer)
+    }
+
+    // Removes and checks transfer `transfer` if it is complete.
+    fn complete(&mut self, transfer: u64) -> Result<Option<File>> {
+        let count = match self.transfers[&transfer].attachment {
+            Some(Attachment { content: Content::Chunked { count, .. }, .. }) => count,
+            _ => return Ok(None)
+        };
+        if self.transfers[&transfer].chunks.len() < count as usize {
+            return Ok(None);
+        }
+        let mut entry = self.transfers.remove(&transfer).unwrap();
+        self.pending_bytes -= entry.received;
+        let mut data = Vec::with_capacity(entry.received as usize);
+        for index in 0..count {
+            data.extend_from_slice(&entry.chunks.remove(&index).unwrap());
+        }
+        finish(&entry.attachment.unwrap(), data).map(Some)
+    }
+}
+
+fn finish(attachment: &Attachment, data: Vec<u8>) -> Result<File> {
+    if data.len() as u64 != attachment.size {
+        return Err(Error::AttachmentSizeMismatch(data.len() as u64));
+    }
+    if sha256(&data) != attachment.sha256 {
+        return Err(Error::DigestMismatch);
+    }
+    Ok(File {
+        filename: attachment.filename.clone(),
+        mime_type: attachment.mime_type.clone(),
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use super::super::{Attachment, Chunk, Content, Message};
+    use super::super::decoder::Decoder;
+    use super::super::encoder::Encoder;
+    use super::super::error::{Error, ReassemblyLimit};
+    use super::{File, Reassembler, ReassemblyLimits};
+
+    fn file(data: Vec<u8>) -> File {
+        File { filename: String::from("f.bin"), mime_type: String::from("application/x"), data }
+    }
+
+    #[test]
+    fn sha256() {
+        assert_eq!(
+            &super::sha256(b"abc")[..],
+            &b"\xba\x78\x16\xbf\x8f\x01\xcf\xea\x41\x41\x40\xde\x5d\xae\x22\x23\
+               \xb0\x03\x61\xa3\x96\x17\x7a\x9c\xb4\x10\xff\x61\xf2\x00\x15\xad"[..]
+        );
+    }
+
+    #[test]
+    fn reassemble_inline() {
+        let data = b"inline".to_vec();
+        let attachment = Attachment::inline(
+            String::from("f.bin"), String::from("application/x"), data.clone()
+        );
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.add_attachment(attachment).unwrap(), Some(file(data)));
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn reassemble_chunked() {
+        let data: Vec<u8> = (0..300000u32).map(|i| i as u8).collect();
+        let (attachment, chunks) = Attachment::chunked(
+            String::from("f.bin"), String::from("application/x"), &data, 9, 70000
+        );
+        assert_eq!(chunks.len(), 5);
+
+        // Send everything over the wire, with a chunk overtaking the announcement
+        let mut buf = BytesMut::new();
+        Message::Chunk(chunks[3].clone()).encode_into(&mut buf);
+        Message::Attachment(attachment).encode_into(&mut buf);
+        for &i in &[4, 0, 2, 1] {
+            Message::Chunk(chunks[i].clone()).encode_into(&mut buf);
+        }
+
+        let mut reassembler = Reassembler::new();
+        let mut files = Vec::new();
+        while !buf.is_empty() {
+            let done = match Message::decode_from(&mut buf).unwrap() {
+                Message::Attachment(a) => reassembler.add_attachment(a).unwrap(),
+                Message::Chunk(c) => reassembler.add_chunk(c).unwrap(),
+                other => panic!("unexpected message: {:?}", other)
+            };
+            files.extend(done);
+        }
+        assert_eq!(files, vec![file(data)]);
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn reassemble_empty() {
+        let (attachment, chunks) = Attachment::chunked(
+            String::from("f.bin"), String::from("application/x"), b"", 1, 10
+        );
+        assert!(chunks.is_empty());
+        assert_eq!(Reassembler::new().add_attachment(attachment).unwrap(), Some(file(vec![])));
+    }
+
+    #[test]
+    fn reassemble_invalid() {
+        let (attachment, chunks) = Attachment::chunked(
+            String::from("f.bin"), String::from("application/x"), b"0123456789", 1, 4
+        );
+        {
+            let mut reassembler = Reassembler::new();
+            reassembler.add_attachment(attachment.clone()).unwrap();
+            reassembler.add_chunk(chunks[0].clone()).unwrap();
+            match reassembler.add_chunk(chunks[0].clone()) {
+                Err(Error::InvalidChunk(0)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+            assert_eq!(reassembler.pending(), 0);
+        }
+        {
+            let mut reassembler = Reassembler::new();
+            reassembler.add_chunk(Chunk { transfer: 1, index: 3, data: vec![] }).unwrap();
+            match reassembler.add_attachment(attachment.clone()) {
+                Err(Error::InvalidChunk(3)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+        {
+            let mut reassembler = Reassembler::new();
+            reassembler.add_attachment(attachment.clone()).unwrap();
+            reassembler.add_chunk(chunks[0].clone()).unwrap();
+            reassembler.add_chunk(chunks[1].clone()).unwrap();
+            match reassembler.add_chunk(Chunk { transfer: 1, index: 2, data: b"89!".to_vec() }) {
+                Err(Error::AttachmentSizeMismatch(11)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+        {
+            let mut reassembler = Reassembler::new();
+            reassembler.add_attachment(attachment.clone()).unwrap();
+            reassembler.add_chunk(chunks[0].clone()).unwrap();
+            reassembler.add_chunk(chunks[1].clone()).unwrap();
+            match reassembler.add_chunk(Chunk { transfer: 1, index: 2, data: b"8!".to_vec() }) {
+                Err(Error::DigestMismatch) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+        {
+            let mut attachment = attachment.clone();
+            attachment.content = Content::Inline(b"012345678".to_vec());
+            match Reassembler::new().add_attachment(attachment) {
+                Err(Error::AttachmentSizeMismatch(9)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+    }
+
+    #[test]
+    fn reassemble_limits() {
+        let limits = ReassemblyLimits { max_transfers: 2, max_chunks: 3, max_pending_bytes: 10 };
+        let chunk = |transfer, index, len| Chunk { transfer, index, data: vec![0; len] };
+        {
+            let mut reassembler = Reassembler::with_limits(limits.clone());
+            reassembler.add_chunk(chunk(1, 0, 1)).unwrap();
+            reassembler.add_chunk(chunk(2, 0, 1)).unwrap();
+            match reassembler.add_chunk(chunk(3, 0, 1)) {
+                Err(Error::ReassemblyLimitExceeded(ReassemblyLimit::Transfers)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+            // Existing transfers may go on, and cancelling one makes room for another
+            reassembler.add_chunk(chunk(2, 1, 1)).unwrap();
+            assert!(reassembler.cancel(1));
+            assert!(!reassembler.cancel(1));
+            reassembler.add_chunk(chunk(3, 0, 1)).unwrap();
+            assert_eq!(reassembler.pending(), 2);
+        }
+        {
+            let mut reassembler = Reassembler::with_limits(limits.clone());
+            for index in 0..3 {
+                reassembler.add_chunk(chunk(1, index, 1)).unwrap();
+            }
+            match reassembler.add_chunk(chunk(1, 3, 1)) {
+                Err(Error::ReassemblyLimitExceeded(ReassemblyLimit::Chunks)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+            assert_eq!(reassembler.pending(), 0);
+        }
+        {
+            // Bytes are counted over all transfers, and released when a transfer fails
+            let mut reassembler = Reassembler::with_limits(limits.clone());
+            reassembler.add_chunk(chunk(1, 0, 6)).unwrap();
+            match reassembler.add_chunk(chunk(2, 0, 5)) {
+                Err(Error::ReassemblyLimitExceeded(ReassemblyLimit::Bytes)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+            reassembler.add_chunk(chunk(3, 0, 4)).unwrap();
+        }
+        {
+            let (attachment, _) = Attachment::chunked(
+                String::from("f.bin"), String::from("application/x"), &[0; 11], 1, 4
+            );
+            match Reassembler::with_limits(limits.clone()).add_attachment(attachment) {
+                Err(Error::ReassemblyLimitExceeded(ReassemblyLimit::Bytes)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+            let (attachment, _) = Attachment::chunked(
+                String::from("f.bin"), String::from("application/x"), &[0; 8], 1, 2
+            );
+            match Reassembler::with_limits(limits).add_attachment(attachment) {
+                Err(Error::ReassemblyLimitExceeded(ReassemblyLimit::Chunks)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+    }
+}
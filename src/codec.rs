@@ -0,0 +1,131 @@
+use bytes::BytesMut;
+use tokio_util::codec;
+use super::Message;
+use super::decoder::{DecodeOptions, Result};
+use super::encoder::Encoder;
+use super::error::Error;
+use super::stream::StreamDecoder;
+
+/// Codec for tokio's framing layer, so `Framed<T, ItreCodec>` yields and accepts `Message`s.
+///
+/// Incoming bytes are handled by a `StreamDecoder`, so messages split across reads are
+/// resumed instead of being scanned from the start on every read. Decoding errors are
+/// wrapped in an `Error::Context` as described there, so match on `Error::into_kind` to
+/// handle a specific error.
+#[derive(Default)]
+pub struct ItreCodec {
+    decoder: StreamDecoder,
+}
+
+impl ItreCodec {
+    pub fn new() -> ItreCodec {
+        ItreCodec::default()
+    }
+
+    pub fn with_options(opts: DecodeOptions) -> ItreCodec {
+        ItreCodec {
+            decoder: StreamDecoder::with_options(opts),
+        }
+    }
+}
+
+impl codec::Decoder for ItreCodec {
+    type Item = Message;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>> {
+        self.decoder.decode(src)
+    }
+}
+
+impl codec::Encoder<Message> for ItreCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<()> {
+        codec::Encoder::encode(self, &item, dst)
+    }
+}
+
+impl<'a> codec::Encoder<&'a Message> for ItreCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: &'a Message, dst: &mut BytesMut) -> Result<()> {
+        dst.reserve(item.encoded_len());
+        item.encode_into(dst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{self, AsyncWriteExt};
+    use tokio_util::codec::{Framed, FramedRead, FramedWrite};
+    use super::super::consts;
+    use super::super::{Message, Emo};
+    use super::super::encoder::Encoder;
+    use super::super::error::Error;
+    use super::ItreCodec;
+
+    fn sample_messages() -> Vec<Message> {
+        vec![
+            Message::Text(String::from("ITRE解码测试")),
+            Message::Compound((0..300).map(|_| Message::Emo(Emo::Laugh)).collect()),
+            Message::Image(consts::MESSAGE_IMAGE_FORMAT_PNG, vec![0x89; 200000]),
+            Message::Nop,
+        ]
+    }
+
+    #[tokio::test]
+    async fn framed_roundtrip() {
+        // A small duplex buffer forces messages to be split across reads
+        let (client, server) = io::duplex(64);
+        let msgs = sample_messages();
+        let expected = sample_messages();
+        let writer = tokio::spawn(async move {
+            let mut framed = FramedWrite::new(client, ItreCodec::new());
+            for msg in msgs {
+                framed.send(msg).await.unwrap();
+            }
+        });
+        let mut framed = FramedRead::new(server, ItreCodec::new());
+        let mut decoded = Vec::new();
+        while let Some(msg) = framed.next().await {
+            decoded.push(msg.unwrap());
+        }
+        writer.await.unwrap();
+        assert_eq!(decoded, expected);
+    }
+
+    #[tokio::test]
+    async fn framed_echo() {
+        let (client, server) = io::duplex(1024);
+        let echo = tokio::spawn(async move {
+            let mut framed = Framed::new(server, ItreCodec::new());
+            while let Some(msg) = framed.next().await {
+                framed.send(msg.unwrap()).await.unwrap();
+            }
+        });
+        let mut framed = Framed::new(client, ItreCodec::new());
+        for msg in &sample_messages() {
+            framed.send(msg).await.unwrap();
+            assert_eq!(&framed.next().await.unwrap().unwrap(), msg);
+        }
+        drop(framed);
+        echo.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn framed_truncated_stream() {
+        let (mut client, server) = io::duplex(1024);
+        let mut buf = bytes::BytesMut::new();
+        Message::Text(String::from("Hello")).encode_into(&mut buf);
+        client.write_all(&buf[..4]).await.unwrap();
+        drop(client);
+        let mut framed = FramedRead::new(server, ItreCodec::new());
+        match framed.next().await {
+            Some(Err(Error::IOError(_))) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+}
@@ -5,12 +5,95 @@ pub const COMPOUND_SLICE_MAX_LENGTH: u8          = 0xfe;
 pub const COMPOUND_SLICE_MAX_LENGTH_S: usize     = COMPOUND_SLICE_MAX_LENGTH as usize;
 pub const COMPOUND_OVERFLOW_FLAG: u8             = 0xff;
 
+pub const FRAME_MAGIC: &[u8; 4]                  = b"ITRE";
+pub const PROTOCOL_VERSION_0_1: u8               = 1;
+
 pub const MESSAGE_TYPE_CODE_NOP: u8              = 0;
+pub const MESSAGE_TYPE_CODE_PING: u8             = 1;
+pub const MESSAGE_TYPE_CODE_PONG: u8             = 2;
+pub const MESSAGE_TYPE_CODE_ACK: u8              = 3;
+pub const MESSAGE_TYPE_CODE_TYPING_START: u8     = 4;
+pub const MESSAGE_TYPE_CODE_TYPING_STOP: u8      = 5;
+pub const MESSAGE_TYPE_CODE_READ_RECEIPT: u8     = 6;
+pub const MESSAGE_TYPE_CODE_CLOSE: u8            = 7;
+pub const MESSAGE_TYPE_CODE_ERROR: u8            = 8;
 pub const MESSAGE_TYPE_CODE_TEXT: u8             = 128;
+pub const MESSAGE_TYPE_CODE_RICH_TEXT: u8        = 129;
 pub const MESSAGE_TYPE_CODE_EMO: u8              = 130;
+pub const MESSAGE_TYPE_CODE_ATTACHMENT: u8       = 131;
+pub const MESSAGE_TYPE_CODE_CHUNK: u8            = 132;
+pub const MESSAGE_TYPE_CODE_LOCATION: u8         = 133;
 pub const MESSAGE_TYPE_CODE_IMAGE: u8            = 140;
+pub const MESSAGE_TYPE_CODE_VOICE: u8            = 141;
 pub const MESSAGE_TYPE_CODE_COMPOUND: u8         = 250;
+pub const MESSAGE_TYPE_CODE_BINARY: u8           = 251;
+
+pub const MESSAGE_TYPE_CODE_EXTENSION_MIN: u8    = 129;
+pub const MESSAGE_TYPE_CODE_EXTENSION_MAX: u8    = 249;
+pub const ENVELOPE_HEADER_LENGTH: usize          = 4;
+
+pub const CLOSE_REASON_NORMAL: u16               = 0;
+pub const CLOSE_REASON_GOING_AWAY: u16           = 1;
+pub const CLOSE_REASON_PROTOCOL_ERROR: u16       = 2;
+
+pub const TEXT_STYLE_CODE_BOLD: u8               = 0;
+pub const TEXT_STYLE_CODE_ITALIC: u8             = 1;
+pub const TEXT_STYLE_CODE_CODE: u8               = 2;
+pub const TEXT_STYLE_CODE_STRIKETHROUGH: u8      = 3;
+pub const TEXT_STYLE_CODE_LINK: u8               = 4;
+pub const TEXT_STYLE_CODE_MENTION: u8            = 5;
+
+pub const MESSAGE_VOICE_CODEC_OPUS: u8           = 0;
+pub const MESSAGE_VOICE_CODEC_AAC: u8            = 1;
+
+pub const LATITUDE_MAX_MICRODEGREES: i32         = 90_000_000;
+pub const LONGITUDE_MAX_MICRODEGREES: i32        = 180_000_000;
+
+pub const ATTACHMENT_CONTENT_INLINE: u8          = 0;
+pub const ATTACHMENT_CONTENT_CHUNKED: u8         = 1;
+pub const SHA256_LENGTH: usize                   = 32;
 
 pub const MESSAGE_EMO_CODE_NOP: u8               = 0;
 pub const MESSAGE_EMO_CODE_LAUGH: u8             = 1;
-pub const MESSAGE_EMO_CODE_CRY: u8               = 2;
\ No newline at end of file
+pub const MESSAGE_EMO_CODE_CRY: u8               = 2;
+pub const MESSAGE_EMO_CODE_CUSTOM: u8            = 240;
+
+pub const MESSAGE_IMAGE_FORMAT_PNG: u8           = 0;
+pub const MESSAGE_IMAGE_FORMAT_JPEG: u8          = 1;
+pub const MESSAGE_IMAGE_FORMAT_GIF: u8           = 2;
+pub const MESSAGE_IMAGE_FORMAT_WEBP: u8          = 3;
+
+/// Codes in the extension range that are taken by built-in messages.
+pub const BUILTIN_EXTENSION_RANGE_TYPE_CODES: &[u8] = &[
+    MESSAGE_TYPE_CODE_RICH_TEXT,
+    MESSAGE_TYPE_CODE_EMO,
+    MESSAGE_TYPE_CODE_ATTACHMENT,
+    MESSAGE_TYPE_CODE_CHUNK,
+    MESSAGE_TYPE_CODE_LOCATION,
+    MESSAGE_TYPE_CODE_IMAGE,
+    MESSAGE_TYPE_CODE_VOICE,
+];
+
+/// Whether applications may register `code` for their own message types.
+pub fn is_extension_type_code(code: u8) -> bool {
+    (MESSAGE_TYPE_CODE_EXTENSION_MIN..=MESSAGE_TYPE_CODE_EXTENSION_MAX).contains(&code) &&
+        !BUILTIN_EXTENSION_RANGE_TYPE_CODES.contains(&code)
+}
+
+/// Whether `code` is taken by a built-in message, so `Message::Unknown` cannot carry it.
+pub fn is_builtin_type_code(code: u8) -> bool {
+    !is_enveloped_type_code(code) ||
+        (MESSAGE_TYPE_CODE_PING..=MESSAGE_TYPE_CODE_ERROR).contains(&code) ||
+        BUILTIN_EXTENSION_RANGE_TYPE_CODES.contains(&code) ||
+        code == MESSAGE_TYPE_CODE_BINARY
+}
+
+/// Whether messages with type code `code` use the length-delimited envelope.
+pub fn is_enveloped_type_code(code: u8) -> bool {
+    !matches!(code,
+              MESSAGE_TYPE_CODE_NOP |
+              MESSAGE_TYPE_CODE_TEXT |
+              MESSAGE_TYPE_CODE_EMO |
+              MESSAGE_TYPE_CODE_IMAGE |
+              MESSAGE_TYPE_CODE_COMPOUND)
+}
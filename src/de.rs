@@ -0,0 +1,440 @@
+//! Serde `Deserializer` that reads Rust values back out of `Message` trees.
+//!
+//! It follows the mapping rules documented in `ser`. Since field names and types are not
+//! encoded, the format is not self-describing: `deserialize_any` only distinguishes `Nop`
+//! (unit), `Text` (string), `Compound` (sequence) and `Binary` (byte array).
+
+use std::str::FromStr;
+use std::vec;
+use bytes::BytesMut;
+use serde::de::{self, Deserialize, DeserializeOwned, DeserializeSeed, Visitor};
+use serde::de::value::StringDeserializer;
+use super::Message;
+use super::decoder::{Decoder, DecodeOptions};
+use super::error::SerdeError;
+
+pub type Result<T> = std::result::Result<T, SerdeError>;
+
+/// Converts a `Message` tree into a `T`.
+pub fn from_message<T: DeserializeOwned>(msg: Message) -> Result<T> {
+    T::deserialize(Deserializer::new(msg))
+}
+
+/// Decodes a message from the front of `buf` and converts it into a `T`.
+pub fn from_bytes<T: DeserializeOwned>(buf: &mut BytesMut) -> Result<T> {
+    from_bytes_with(buf, &DecodeOptions::default())
+}
+
+/// Same as `from_bytes`, but honours the given options, e.g. limits raised for values with
+/// more than `DecodeLimits::max_children` elements.
+pub fn from_bytes_with<T: DeserializeOwned>(buf: &mut BytesMut, opts: &DecodeOptions) -> Result<T> {
+    from_message(Message::decode_with(buf, opts)?)
+}
+
+pub struct Deserializer {
+    msg: Message,
+}
+
+impl Deserializer {
+    pub fn new(msg: Message) -> Deserializer {
+        Deserializer { msg }
+    }
+
+    fn into_text(self, expected: &'static str) -> Result<String> {
+        match self.msg {
+            Message::Text(s) => Ok(s),
+            msg => Err(mismatch(&msg, expected))
+        }
+    }
+
+    fn into_bytes(self, expected: &'static str) -> Result<Vec<u8>> {
+        match self.msg {
+            Message::Binary(bytes) => Ok(bytes),
+            msg => Err(mismatch(&msg, expected))
+        }
+    }
+
+    fn into_compound(self, expected: &'static str) -> Result<Vec<Message>> {
+        match self.msg {
+            Message::Compound(msgs) => Ok(msgs),
+            msg => Err(mismatch(&msg, expected))
+        }
+    }
+
+    fn parse<T: FromStr>(self, expected: &'static str) -> Result<T> {
+        let s = self.into_text(expected)?;
+        match s.parse() {
+            Ok(v) => Ok(v),
+            Err(_) => Err(de::Error::invalid_value(de::Unexpected::Str(&s), &expected))
+        }
+    }
+}
+
+fn mismatch(msg: &Message, expected: &'static str) -> SerdeError {
+    let unexpected = match *msg {
+        Message::Nop => de::Unexpected::Unit,
+        Message::Text(ref s) => de::Unexpected::Str(s),
+        Message::Compound(_) => de::Unexpected::Seq,
+        Message::Binary(ref bytes) => de::Unexpected::Bytes(bytes),
+        _ => de::Unexpected::Other("non-serde message"),
+    };
+    de::Error::invalid_type(unexpected, &expected)
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident, $expected:expr;)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+                visitor.$visit(self.parse($expected)?)
+            }
+        )*
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer {
+    type Error = SerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.msg {
+            Message::Nop => visitor.visit_unit(),
+            Message::Text(s) => visitor.visit_string(s),
+            Message::Compound(msgs) => visitor.visit_seq(SeqAccess::new(msgs)),
+            Message::Binary(bytes) => visitor.visit_byte_buf(bytes),
+            msg => Err(mismatch(&msg, "a Nop, Text, Compound or Binary message"))
+        }
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool, "a boolean";
+        deserialize_i8 => visit_i8, "an i8";
+        deserialize_i16 => visit_i16, "an i16";
+        deserialize_i32 => visit_i32, "an i32";
+        deserialize_i64 => visit_i64, "an i64";
+        deserialize_i128 => visit_i128, "an i128";
+        deserialize_u8 => visit_u8, "a u8";
+        deserialize_u16 => visit_u16, "a u16";
+        deserialize_u32 => visit_u32, "a u32";
+        deserialize_u64 => visit_u64, "a u64";
+        deserialize_u128 => visit_u128, "a u128";
+        deserialize_f32 => visit_f32, "an f32";
+        deserialize_f64 => visit_f64, "an f64";
+        deserialize_char => visit_char, "a char";
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_string(self.into_text("a string")?)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_byte_buf(self.into_bytes("a byte array")?)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.msg {
+            Message::Nop => visitor.visit_none(),
+            Message::Compound(mut msgs) if msgs.len() == 1 => {
+                visitor.visit_some(Deserializer::new(msgs.remove(0)))
+            },
+            msg => Err(mismatch(&msg, "a Nop or single-element Compound message"))
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.msg {
+            Message::Nop => visitor.visit_unit(),
+            msg => Err(mismatch(&msg, "a Nop message"))
+        }
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, _name: &'static str,
+                                                visitor: V) -> Result<V::Value> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str,
+                                                   visitor: V) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let msgs = self.into_compound("a sequence")?;
+        visitor.visit_seq(SeqAccess::new(msgs))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, _len: usize,
+                                                 visitor: V) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let msgs = self.into_compound("a map")?;
+        visitor.visit_map(MapAccess { entries: msgs.into_iter(), value: None })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str,
+                                           _fields: &'static [&'static str],
+                                           visitor: V) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str,
+                                         _variants: &'static [&'static str],
+                                         visitor: V) -> Result<V::Value> {
+        match self.msg {
+            Message::Text(name) => visitor.visit_enum(StringDeserializer::<SerdeError>::new(name)),
+            Message::Compound(mut msgs) if msgs.len() == 2 => {
+                let value = msgs.pop().unwrap();
+                let name = Deserializer::new(msgs.pop().unwrap()).into_text("a variant name")?;
+                visitor.visit_enum(EnumAccess { name, value })
+            },
+            msg => Err(mismatch(&msg, "a Text or two-element Compound message"))
+        }
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+struct SeqAccess {
+    msgs: vec::IntoIter<Message>,
+}
+
+impl SeqAccess {
+    fn new(msgs: Vec<Message>) -> SeqAccess {
+        SeqAccess { msgs: msgs.into_iter() }
+    }
+}
+
+impl<'de> de::SeqAccess<'de> for SeqAccess {
+    type Error = SerdeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        match self.msgs.next() {
+            Some(msg) => seed.deserialize(Deserializer::new(msg)).map(Some),
+            None => Ok(None)
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.msgs.len())
+    }
+}
+
+struct MapAccess {
+    entries: vec::IntoIter<Message>,
+    value: Option<Message>,
+}
+
+impl<'de> de::MapAccess<'de> for MapAccess {
+    type Error = SerdeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        let mut entry = match self.entries.next() {
+            Some(Message::Compound(entry)) if entry.len() == 2 => entry,
+            Some(msg) => return Err(mismatch(&msg, "a two-element Compound message")),
+            None => return Ok(None)
+        };
+        self.value = entry.pop();
+        seed.deserialize(Deserializer::new(entry.pop().unwrap())).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        match self.value.take() {
+            Some(msg) => seed.deserialize(Deserializer::new(msg)),
+            None => Err(de::Error::custom("map value requested before its key"))
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+struct EnumAccess {
+    name: String,
+    value: Message,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumAccess {
+    type Error = SerdeError;
+    type Variant = Deserializer;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Deserializer)> {
+        let variant = seed.deserialize(StringDeserializer::<SerdeError>::new(self.name))?;
+        Ok((variant, Deserializer::new(self.value)))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Deserializer {
+    type Error = SerdeError;
+
+    fn unit_variant(self) -> Result<()> {
+        Deserialize::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str],
+                                       visitor: V) -> Result<V::Value> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use proptest::prelude::*;
+    use serde::{Deserialize, Serialize};
+    use super::super::Message;
+    use super::super::decoder::{DecodeOptions, DecodeLimits};
+    use super::super::error::{Error, Limit, SerdeError};
+    use super::super::ser::{to_bytes, to_message};
+    use super::{from_bytes, from_bytes_with, from_message};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Unit;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Id(u64);
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    enum Shape {
+        Empty,
+        Circle(u32),
+        Line(i16, i16),
+        Rect { w: u8, h: u8 },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Chat {
+        id: Id,
+        from: String,
+        text: Option<String>,
+        tags: Vec<String>,
+        read_by: BTreeMap<String, bool>,
+        shapes: Vec<Shape>,
+        score: f64,
+        flag: char,
+        unit: Unit,
+        nothing: (),
+        pair: (i8, u128),
+        #[serde(with = "serde_bytes")]
+        avatar: Vec<u8>,
+    }
+
+    fn shape() -> impl Strategy<Value = Shape> {
+        prop_oneof![
+            Just(Shape::Empty),
+            any::<u32>().prop_map(Shape::Circle),
+            any::<(i16, i16)>().prop_map(|(a, b)| Shape::Line(a, b)),
+            any::<(u8, u8)>().prop_map(|(w, h)| Shape::Rect { w, h }),
+        ]
+    }
+
+    fn chat() -> impl Strategy<Value = Chat> {
+        (
+            any::<u64>(),
+            ".*",
+            proptest::option::of(".*"),
+            proptest::collection::vec(".*", 0..300),
+            proptest::collection::btree_map(".*", any::<bool>(), 0..8),
+            proptest::collection::vec(shape(), 0..8),
+            any::<f64>().prop_filter("NaN never compares equal", |f| !f.is_nan()),
+            any::<char>(),
+            any::<(i8, u128)>(),
+            proptest::collection::vec(any::<u8>(), 0..300),
+        ).prop_map(|(id, from, text, tags, read_by, shapes, score, flag, pair, avatar)| Chat {
+            id: Id(id), from, text, tags, read_by, shapes, score, flag,
+            unit: Unit, nothing: (), pair, avatar,
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip_struct(chat in chat()) {
+            let mut buf = to_bytes(&chat).unwrap();
+            let decoded: Chat = from_bytes(&mut buf).unwrap();
+            prop_assert_eq!(decoded, chat);
+            prop_assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn roundtrip_nested_options(v in any::<Option<Option<Vec<Option<u8>>>>>()) {
+            let decoded: Option<Option<Vec<Option<u8>>>> =
+                from_message(to_message(&v).unwrap()).unwrap();
+            prop_assert_eq!(decoded, v);
+        }
+    }
+
+    #[test]
+    fn deserialize_mismatch() {
+        match from_message::<u8>(Message::Text(String::from("256"))) {
+            Err(SerdeError::Custom(_)) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+        match from_message::<String>(Message::Nop) {
+            Err(SerdeError::Custom(_)) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn roundtrip_large() {
+        // A single payload, not one message per byte
+        let bytes = serde_bytes::ByteBuf::from(vec![7; 2 * 1024 * 1024]);
+        let mut buf = to_bytes(&bytes).unwrap();
+        assert_eq!(buf.len(), 1 + 4 + bytes.len());
+        assert_eq!(from_bytes::<serde_bytes::ByteBuf>(&mut buf).unwrap(), bytes);
+
+        let items = vec![1u8; 70000];
+        let buf = to_bytes(&items).unwrap();
+        match from_bytes::<Vec<u8>>(&mut buf.clone()) {
+            Err(SerdeError::Decode(ref e)) => match *e.kind() {
+                Error::LimitExceeded(Limit::Children) => {},
+                ref other => panic!("unexpected error: {:?}", other)
+            },
+            other => panic!("unexpected result: {:?}", other)
+        }
+        let opts = DecodeOptions {
+            limits: DecodeLimits { max_children: items.len(), ..DecodeLimits::default() },
+            ..DecodeOptions::default()
+        };
+        assert_eq!(from_bytes_with::<Vec<u8>>(&mut buf.clone(), &opts).unwrap(), items);
+    }
+
+    #[test]
+    fn deserialize_decode_error() {
+        let mut buf = bytes::BytesMut::from(&b"\x80\x00\x05Hel"[..]);
+        match from_bytes::<String>(&mut buf) {
+            Err(SerdeError::Decode(_)) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+}
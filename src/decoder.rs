@@ -1,98 +1,886 @@
 use std;
-use std::str;
-use bytes::{BytesMut};
+use bytes::{Buf, BytesMut};
 use byteorder::{ByteOrder, BigEndian};
 use super::consts;
-use super::{Message, Emo};
-use super::error::Error;
+use std::ops::{Deref, RangeInclusive};
+use std::sync::Arc;
+use super::{Message, Control, Emo, Envelope, ProtocolVersion, Span, Style, Attachment, Content,
+            Chunk, Location};
+use super::error::{Error, Limit};
+use super::registry::Registry;
+use super::view::{MessageView, EmoView, AttachmentView, ContentView, ChunkView};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Knobs that change how lenient the decoder is. `DecodeOptions::default()` is strict.
+#[derive(Debug, Clone)]
+pub struct DecodeOptions {
+    /// Replace invalid UTF-8 in texts with U+FFFD instead of failing with `Error::InvalidUtf8`.
+    pub lossy_utf8: bool,
+    /// Protocol versions accepted in frame headers. Defaults to the current version only.
+    pub versions: RangeInclusive<ProtocolVersion>,
+    /// Expect a frame header at the start of streams decoded by `stream::StreamDecoder`, and
+    /// so by `codec::ItreCodec` and `io::MessageReader`. Defaults to false.
+    pub frame_header: bool,
+    /// Application-defined message types to accept besides the built-in ones.
+    pub registry: Option<Arc<Registry>>,
+    pub limits: DecodeLimits,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        DecodeOptions {
+            lossy_utf8: false,
+            versions: ProtocolVersion::CURRENT..=ProtocolVersion::CURRENT,
+            frame_header: false,
+            registry: None,
+            limits: DecodeLimits::default(),
+        }
+    }
+}
+
+/// Bounds on the resources decoding a message may take, to defend against hostile input.
+/// Exceeding one fails with `Error::LimitExceeded`.
+///
+/// The limits apply to each message decoded with `Message::decode_with` (or `Envelope`)
+/// and to each message scanned by `stream::StreamDecoder`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// How deeply compound messages and registered types may be nested. A compound message
+    /// that is not nested in another one has depth 1. Decoding recurses once per level, so
+    /// this also bounds the stack usage. Defaults to 32.
+    pub max_depth: usize,
+    /// Maximum encoded length of a message, including everything nested in it.
+    /// Defaults to 16 MiB.
+    pub max_total_bytes: usize,
+    /// Maximum number of messages nested directly in a compound message, and of elements
+    /// of a `Vec`. Defaults to 65536.
+    pub max_children: usize,
+    /// Maximum length of a text (and of other strings, e.g. file names) in bytes.
+    /// Defaults to 1 MiB.
+    pub max_string_length: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_depth: 32,
+            max_total_bytes: 16 * 1024 * 1024,
+            max_children: 65536,
+            max_string_length: 1024 * 1024,
+        }
+    }
+}
+
 pub trait Decoder {
-    fn decode_from(buf: &mut BytesMut) -> Result<Self> where Self: Sized;
+    /// Decodes a value from the front of `buf`, advancing it past the consumed bytes.
+    ///
+    /// Truncated input yields `Error::UnexpectedEof`, which may be wrapped in an
+    /// `Error::Context` (see `Error::kind`). When an error is returned, `buf` may already have
+    /// been partially advanced.
+    fn decode_from(buf: &mut BytesMut) -> Result<Self> where Self: Sized {
+        Self::decode_with(buf, &DecodeOptions::default())
+    }
+
+    /// Same as `decode_from`, but honours the given options, including in nested messages.
+    fn decode_with(buf: &mut BytesMut, opts: &DecodeOptions) -> Result<Self> where Self: Sized;
+
+    /// Same as `decode_with`, for a value nested in a message being decoded, e.g. the payload
+    /// of a registered type. Types holding messages should pass `ctx` on to them, so that the
+    /// `DecodeLimits` of the outermost message keep applying. The default implementation
+    /// decodes the value afresh.
+    fn decode_nested(buf: &mut BytesMut, ctx: &DecodeContext) -> Result<Self> where Self: Sized {
+        Self::decode_with(buf, ctx.opts())
+    }
 }
 
-impl Decoder for String {
-    fn decode_from(buf: &mut BytesMut) -> Result<Self> {
-        let mut result = String::new();
-        loop {
-            let len = BigEndian::read_u16(&buf[0..2]);
-            buf.advance(2);
-            if len == consts::TEXT_OVERFLOW_FLAG {
-                let slice = Vec::from(&buf[..consts::TEXT_SLICE_MAX_LENGTH_S]);
-                buf.advance(consts::TEXT_SLICE_MAX_LENGTH_S);
-                let s = str::from_utf8(&slice).unwrap();
-                result.push_str(s);
-            } else {
-                let slice = Vec::from(&buf[..len as usize]);
-                buf.advance(len as usize);
-                let s = str::from_utf8(&slice).unwrap();
-                result.push_str(s);
-                break
+/// Where a value being decoded sits in the outermost message: how deeply it is nested and
+/// how many bytes of the outermost message precede it. See `Decoder::decode_nested`.
+#[derive(Clone, Copy)]
+pub struct DecodeContext<'a> {
+    opts: &'a DecodeOptions,
+    nesting: Nesting,
+}
+
+impl<'a> DecodeContext<'a> {
+    /// Context of a value at the front of `buf` that is not nested in any message.
+    pub fn new(opts: &'a DecodeOptions, buf: &BytesMut) -> DecodeContext<'a> {
+        DecodeContext { opts, nesting: Nesting { depth: 0, start: buf.len() } }
+    }
+
+    pub fn opts(&self) -> &'a DecodeOptions {
+        self.opts
+    }
+
+    /// Context of a payload just split off the front of `buf`.
+    pub fn payload(&self, buf: &BytesMut) -> DecodeContext<'a> {
+        // The bytes of the payload follow everything that was consumed before it
+        let start = self.nesting.start - buf.len();
+        DecodeContext { nesting: Nesting { start, ..self.nesting }, ..*self }
+    }
+}
+
+macro_rules! impl_decoder_for_int {
+    ($($t:ty => $get:ident),*) => {
+        $(
+            impl Decoder for $t {
+                fn decode_with(buf: &mut BytesMut, _opts: &DecodeOptions) -> Result<Self> {
+                    ensure(buf, ::std::mem::size_of::<$t>())?;
+                    Ok(buf.$get())
+                }
             }
-        };
-        return Ok(result);
+        )*
+    }
+}
+
+impl_decoder_for_int! {
+    u8 => get_u8, u16 => get_u16, u32 => get_u32, u64 => get_u64,
+    i8 => get_i8, i16 => get_i16, i32 => get_i32, i64 => get_i64
+}
+
+impl Decoder for bool {
+    fn decode_with(buf: &mut BytesMut, _opts: &DecodeOptions) -> Result<Self> {
+        read_flag(buf)
+    }
+}
+
+impl<T: Decoder> Decoder for Vec<T> {
+    fn decode_with(buf: &mut BytesMut, opts: &DecodeOptions) -> Result<Self> {
+        read_vec(buf, opts, |buf| T::decode_with(buf, opts))
+    }
+
+    fn decode_nested(buf: &mut BytesMut, ctx: &DecodeContext) -> Result<Self> {
+        read_vec(buf, ctx.opts, |buf| T::decode_nested(buf, ctx))
+    }
+}
+
+impl<T: Decoder> Decoder for Option<T> {
+    fn decode_with(buf: &mut BytesMut, opts: &DecodeOptions) -> Result<Self> {
+        read_option(buf, |buf| T::decode_with(buf, opts))
+    }
+
+    fn decode_nested(buf: &mut BytesMut, ctx: &DecodeContext) -> Result<Self> {
+        read_option(buf, |buf| T::decode_nested(buf, ctx))
+    }
+}
+
+impl Decoder for ProtocolVersion {
+    fn decode_with(buf: &mut BytesMut, opts: &DecodeOptions) -> Result<Self> {
+        let magic = take(buf, consts::FRAME_MAGIC.len())?;
+        if magic[..] != consts::FRAME_MAGIC[..] {
+            return Err(Error::InvalidFrameMagic(magic.to_vec()));
+        }
+        let version = ProtocolVersion(read_u8(buf)?);
+        if !opts.versions.contains(&version) {
+            return Err(Error::UnsupportedVersion(version.0));
+        }
+        Ok(version)
+    }
+}
+
+/// Errors are returned as `Error::Context`, like for `Message`.
+impl Decoder for Envelope {
+    fn decode_with(buf: &mut BytesMut, opts: &DecodeOptions) -> Result<Self> {
+        Envelope::decode_nested(buf, &DecodeContext::new(opts, buf))
+    }
+
+    fn decode_nested(buf: &mut BytesMut, ctx: &DecodeContext) -> Result<Self> {
+        let start = buf.len();
+        let mut envelope = decode_envelope_header(buf, ctx.opts)
+            .map_err(|e| e.at(start - buf.len()))?;
+        let offset = start - buf.len();
+        envelope.message = Message::decode_nested(buf, ctx)
+            .map_err(|e| e.within(String::from("message"), offset))?;
+        Ok(envelope)
+    }
+}
+
+// Decodes everything of an envelope but the message, which is left as `Message::Nop`.
+fn decode_envelope_header(buf: &mut BytesMut, opts: &DecodeOptions) -> Result<Envelope> {
+    Ok(Envelope {
+        id: u64::decode_with(buf, opts)?,
+        sender: u64::decode_with(buf, opts)?,
+        conversation: u64::decode_with(buf, opts)?,
+        timestamp: read_varint(buf)?,
+        reply_to: Option::decode_with(buf, opts)?,
+        message: Message::Nop,
+    })
+}
+
+impl Decoder for String {
+    fn decode_with(buf: &mut BytesMut, opts: &DecodeOptions) -> Result<Self> {
+        read_text(buf, opts)
+    }
+}
+
+impl Decoder for Style {
+    fn decode_with(buf: &mut BytesMut, opts: &DecodeOptions) -> Result<Self> {
+        read_style(buf, opts)
+    }
+}
+
+/// Does not validate the range, as that needs the text it applies to.
+impl Decoder for Span {
+    fn decode_with(buf: &mut BytesMut, opts: &DecodeOptions) -> Result<Self> {
+        read_span(buf, opts)
+    }
+}
+
+impl Decoder for Attachment {
+    fn decode_with(buf: &mut BytesMut, opts: &DecodeOptions) -> Result<Self> {
+        read_attachment(buf, opts).map(Attachment::from)
+    }
+}
+
+impl Decoder for Content {
+    fn decode_with(buf: &mut BytesMut, opts: &DecodeOptions) -> Result<Self> {
+        read_content(buf, opts).map(Content::from)
+    }
+}
+
+impl Decoder for Chunk {
+    fn decode_with(buf: &mut BytesMut, opts: &DecodeOptions) -> Result<Self> {
+        read_chunk(buf, opts).map(Chunk::from)
+    }
+}
+
+impl Decoder for Location {
+    fn decode_with(buf: &mut BytesMut, opts: &DecodeOptions) -> Result<Self> {
+        read_location(buf, opts)
     }
 }
 
 impl Decoder for Emo {
-    fn decode_from(buf: &mut BytesMut) -> Result<Self> {
-        let emo_code = buf[0];
-        buf.advance(1);
-        let msg = match emo_code {
-            consts::MESSAGE_EMO_CODE_NOP => Emo::Nop,
-            consts::MESSAGE_EMO_CODE_LAUGH => Emo::Laugh,
-            consts::MESSAGE_EMO_CODE_CRY => Emo::Cry,
-            _ => return Err(Error::InvalidEmoCode(emo_code))
+    fn decode_with(buf: &mut BytesMut, opts: &DecodeOptions) -> Result<Self> {
+        read_emo(buf, opts).map(Emo::from)
+    }
+}
+
+// Input the decoding functions below read from. Parts of it are handed out as texts and
+// bytes: `BytesMut` copies them into the owned `String`s and `Vec<u8>`s of `Message`, the
+// inputs of `view` share them instead.
+pub(crate) trait Input: Sized {
+    type Text: Deref<Target = str> + Into<String>;
+    type Bytes: Deref<Target = [u8]>;
+
+    fn remaining(&self) -> &[u8];
+
+    fn advance(&mut self, len: usize);
+
+    // Splits off the first `len` bytes, which must be available.
+    fn split_to(&mut self, len: usize) -> Self;
+
+    fn into_bytes(self) -> Self::Bytes;
+
+    // Splits off the first `len` bytes, which must be available, as `Self::Bytes`.
+    fn split_bytes(&mut self, len: usize) -> Self::Bytes {
+        self.split_to(len).into_bytes()
+    }
+
+    // Bytes that had to be joined from several slices
+    fn joined(bytes: Vec<u8>) -> Self::Bytes;
+
+    fn text(bytes: Self::Bytes, lossy_utf8: bool) -> Result<Self::Text>;
+
+    // Runs `read` on the remaining bytes as a `BytesMut`, as the `Decoder` of a registered
+    // type needs, and advances past what it consumed. The bytes are copied by default.
+    fn with_bytes_mut<T, F>(&mut self, read: F) -> T where F: FnOnce(&mut BytesMut) -> T {
+        let mut copy = BytesMut::from(self.remaining());
+        let result = read(&mut copy);
+        let consumed = self.len() - copy.len();
+        self.advance(consumed);
+        result
+    }
+
+    fn len(&self) -> usize {
+        self.remaining().len()
+    }
+}
+
+impl Input for BytesMut {
+    type Text = String;
+    type Bytes = Vec<u8>;
+
+    fn remaining(&self) -> &[u8] {
+        self
+    }
+
+    fn advance(&mut self, len: usize) {
+        Buf::advance(self, len);
+    }
+
+    fn split_to(&mut self, len: usize) -> BytesMut {
+        BytesMut::split_to(self, len)
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_vec()
+    }
+
+    // The bytes are copied anyway, so `buf` is not split
+    fn split_bytes(&mut self, len: usize) -> Vec<u8> {
+        let bytes = self[..len].to_vec();
+        Buf::advance(self, len);
+        bytes
+    }
+
+    fn joined(bytes: Vec<u8>) -> Vec<u8> {
+        bytes
+    }
+
+    fn text(bytes: Vec<u8>, lossy_utf8: bool) -> Result<String> {
+        match String::from_utf8(bytes) {
+            Ok(s) => Ok(s),
+            Err(e) if lossy_utf8 => Ok(String::from_utf8_lossy(e.as_bytes()).into_owned()),
+            Err(e) => Err(Error::InvalidUtf8(e.utf8_error().valid_up_to()))
+        }
+    }
+
+    fn with_bytes_mut<T, F>(&mut self, read: F) -> T where F: FnOnce(&mut BytesMut) -> T {
+        read(self)
+    }
+}
+
+// Fails with `Error::UnexpectedEof` unless at least `len` bytes are left in `buf`.
+fn ensure<I: Input>(buf: &I, len: usize) -> Result<()> {
+    if buf.len() < len {
+        return Err(Error::UnexpectedEof(len - buf.len()));
+    }
+    Ok(())
+}
+
+fn take<I: Input>(buf: &mut I, len: usize) -> Result<I> {
+    ensure(buf, len)?;
+    Ok(buf.split_to(len))
+}
+
+fn take_bytes<I: Input>(buf: &mut I, len: usize) -> Result<I::Bytes> {
+    ensure(buf, len)?;
+    Ok(buf.split_bytes(len))
+}
+
+// Reads a value of `len` bytes in place, without splitting `buf`.
+fn read_fixed<I: Input, T, F>(buf: &mut I, len: usize, read: F) -> Result<T>
+    where F: FnOnce(&[u8]) -> T {
+    ensure(buf, len)?;
+    let value = read(buf.remaining());
+    buf.advance(len);
+    Ok(value)
+}
+
+fn read_u8<I: Input>(buf: &mut I) -> Result<u8> {
+    read_fixed(buf, 1, |bytes| bytes[0])
+}
+
+fn read_u16<I: Input>(buf: &mut I) -> Result<u16> {
+    read_fixed(buf, 2, BigEndian::read_u16)
+}
+
+fn read_u32<I: Input>(buf: &mut I) -> Result<u32> {
+    read_fixed(buf, 4, BigEndian::read_u32)
+}
+
+fn read_u64<I: Input>(buf: &mut I) -> Result<u64> {
+    read_fixed(buf, 8, BigEndian::read_u64)
+}
+
+fn read_flag<I: Input>(buf: &mut I) -> Result<bool> {
+    match read_u8(buf)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        flag => Err(Error::InvalidFlag(flag))
+    }
+}
+
+fn read_option<I: Input, T, F>(buf: &mut I, read: F) -> Result<Option<T>>
+    where F: FnOnce(&mut I) -> Result<T> {
+    if read_flag(buf)? {
+        Ok(Some(read(buf)?))
+    } else {
+        Ok(None)
+    }
+}
+
+// Reads a count followed by that many items.
+fn read_vec<T, F>(buf: &mut BytesMut, opts: &DecodeOptions, mut read: F) -> Result<Vec<T>>
+    where F: FnMut(&mut BytesMut) -> Result<T> {
+    let len = read_u32(buf)?;
+    if len as usize > opts.limits.max_children {
+        return Err(Error::LimitExceeded(Limit::Children));
+    }
+    // Every item takes up at least a byte
+    ensure(buf, len as usize)?;
+    let mut items = Vec::with_capacity(capacity::<T>(len, buf.len(), opts));
+    for _ in 0..len {
+        let remaining = buf.len();
+        items.push(read(buf)?);
+        if buf.len() == remaining {
+            return Err(Error::EmptyElement);
+        }
+    }
+    Ok(items)
+}
+
+// Reads bytes written by `encoder::encode_byte_vec`, in one piece.
+fn read_byte_vec<I: Input>(buf: &mut I) -> Result<I::Bytes> {
+    let len = read_u32(buf)? as usize;
+    take_bytes(buf, len)
+}
+
+// Unsigned LEB128
+fn read_varint<I: Input>(buf: &mut I) -> Result<u64> {
+    let mut n = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = read_u8(buf)?;
+        // The 10th byte may only hold the single remaining bit
+        if shift == 63 && byte > 1 {
+            return Err(Error::InvalidVarint);
+        }
+        n |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+        shift += 7;
+    }
+}
+
+// Number of elements of type `T` to allocate room for up front, when `len` of them are
+// announced with `remaining` bytes of input left. `len` comes from the input, so the
+// allocation is bounded like the input: every element takes at least one byte of it, and
+// there are at most `DecodeLimits::max_total_bytes` of those.
+pub(crate) fn capacity<T>(len: u32, remaining: usize, opts: &DecodeOptions) -> usize {
+    let bytes = std::cmp::min(remaining, opts.limits.max_total_bytes);
+    std::cmp::min(len as usize, bytes / std::cmp::max(1, std::mem::size_of::<T>()))
+}
+
+// Reads bytes written in slices, like texts, and joins them.
+// Fails with `limit` if they add up to more than `max` bytes.
+fn read_sliced<I: Input>(buf: &mut I, max: usize, limit: Limit) -> Result<I::Bytes> {
+    let mut joined: Option<Vec<u8>> = None;
+    loop {
+        let len = read_u16(buf)?;
+        let slice_len = if len == consts::TEXT_OVERFLOW_FLAG {
+            consts::TEXT_SLICE_MAX_LENGTH_S
+        } else {
+            len as usize
         };
-        return Ok(msg);
+        if joined.as_ref().map_or(0, Vec::len) + slice_len > max {
+            return Err(Error::LimitExceeded(limit));
+        }
+        if len != consts::TEXT_OVERFLOW_FLAG {
+            return match joined {
+                // Not sliced after all, so no need to join
+                None => take_bytes(buf, slice_len),
+                Some(mut bytes) => {
+                    read_into(buf, slice_len, &mut bytes)?;
+                    Ok(I::joined(bytes))
+                }
+            };
+        }
+        read_into(buf, slice_len, joined.get_or_insert_with(Vec::new))?;
     }
 }
 
+// Appends the next `len` bytes of `buf` to `bytes`.
+fn read_into<I: Input>(buf: &mut I, len: usize, bytes: &mut Vec<u8>) -> Result<()> {
+    ensure(buf, len)?;
+    bytes.extend_from_slice(&buf.remaining()[..len]);
+    buf.advance(len);
+    Ok(())
+}
+
+fn read_text<I: Input>(buf: &mut I, opts: &DecodeOptions) -> Result<I::Text> {
+    let bytes = read_sliced(buf, opts.limits.max_string_length, Limit::StringLength)?;
+    // Slices may split a multi-byte character, so only the joined text is validated.
+    I::text(bytes, opts.lossy_utf8)
+}
+
+fn read_string<I: Input>(buf: &mut I, opts: &DecodeOptions) -> Result<String> {
+    Ok(read_text(buf, opts)?.into())
+}
+
+fn read_style<I: Input>(buf: &mut I, opts: &DecodeOptions) -> Result<Style> {
+    let style_code = read_u8(buf)?;
+    let style = match style_code {
+        consts::TEXT_STYLE_CODE_BOLD => Style::Bold,
+        consts::TEXT_STYLE_CODE_ITALIC => Style::Italic,
+        consts::TEXT_STYLE_CODE_CODE => Style::Code,
+        consts::TEXT_STYLE_CODE_STRIKETHROUGH => Style::Strikethrough,
+        consts::TEXT_STYLE_CODE_LINK => Style::Link(read_string(buf, opts)?),
+        consts::TEXT_STYLE_CODE_MENTION => Style::Mention(read_u64(buf)?),
+        _ => return Err(Error::InvalidStyleCode(style_code))
+    };
+    Ok(style)
+}
+
+fn read_span<I: Input>(buf: &mut I, opts: &DecodeOptions) -> Result<Span> {
+    Ok(Span {
+        start: read_u32(buf)?,
+        end: read_u32(buf)?,
+        style: read_style(buf, opts)?,
+    })
+}
+
+fn read_spans<I: Input>(buf: &mut I, opts: &DecodeOptions) -> Result<Vec<Span>> {
+    let len = read_u32(buf)?;
+    let mut spans = Vec::with_capacity(capacity::<Span>(len, buf.len(), opts));
+    for _ in 0..len {
+        spans.push(read_span(buf, opts)?);
+    }
+    Ok(spans)
+}
+
+// Fails unless every span delimits whole characters within `text`.
+pub(crate) fn check_spans(text: &str, spans: &[Span]) -> Result<()> {
+    for span in spans {
+        let (start, end) = (span.start as usize, span.end as usize);
+        if start > end || !text.is_char_boundary(start) || !text.is_char_boundary(end) {
+            return Err(Error::InvalidSpan(span.start, span.end));
+        }
+    }
+    Ok(())
+}
+
+fn read_attachment<I: Input>(buf: &mut I,
+                             opts: &DecodeOptions) -> Result<AttachmentView<I::Text, I::Bytes>> {
+    let filename = read_text(buf, opts)?;
+    let mime_type = read_text(buf, opts)?;
+    let size = read_u64(buf)?;
+    let sha256 = read_fixed(buf, consts::SHA256_LENGTH, |bytes| {
+        let mut sha256 = [0; consts::SHA256_LENGTH];
+        sha256.copy_from_slice(&bytes[..consts::SHA256_LENGTH]);
+        sha256
+    })?;
+    let content = read_content(buf, opts)?;
+    Ok(AttachmentView { filename, mime_type, size, sha256, content })
+}
+
+fn read_content<I: Input>(buf: &mut I, opts: &DecodeOptions) -> Result<ContentView<I::Bytes>> {
+    let kind = read_u8(buf)?;
+    let content = match kind {
+        consts::ATTACHMENT_CONTENT_INLINE => {
+            ContentView::Inline(read_sliced(buf, opts.limits.max_total_bytes, Limit::TotalBytes)?)
+        },
+        consts::ATTACHMENT_CONTENT_CHUNKED => ContentView::Chunked {
+            transfer: read_u64(buf)?,
+            count: read_u32(buf)?,
+        },
+        _ => return Err(Error::InvalidContentKind(kind))
+    };
+    Ok(content)
+}
+
+fn read_chunk<I: Input>(buf: &mut I, opts: &DecodeOptions) -> Result<ChunkView<I::Bytes>> {
+    Ok(ChunkView {
+        transfer: read_u64(buf)?,
+        index: read_u32(buf)?,
+        data: read_sliced(buf, opts.limits.max_total_bytes, Limit::TotalBytes)?,
+    })
+}
+
+fn read_location<I: Input>(buf: &mut I, opts: &DecodeOptions) -> Result<Location> {
+    let latitude = read_u32(buf)? as i32;
+    let longitude = read_u32(buf)? as i32;
+    let (lat_max, lon_max) = (consts::LATITUDE_MAX_MICRODEGREES, consts::LONGITUDE_MAX_MICRODEGREES);
+    if !(-lat_max..=lat_max).contains(&latitude) || !(-lon_max..=lon_max).contains(&longitude) {
+        return Err(Error::InvalidCoordinates(latitude, longitude));
+    }
+    Ok(Location {
+        latitude,
+        longitude,
+        accuracy: read_option(buf, read_u32)?,
+        name: read_option(buf, |buf| read_string(buf, opts))?,
+        live_until: read_option(buf, read_u64)?,
+    })
+}
+
+fn read_image<I: Input>(buf: &mut I, opts: &DecodeOptions) -> Result<(u8, I::Bytes)> {
+    let format = read_u8(buf)?;
+    match format {
+        consts::MESSAGE_IMAGE_FORMAT_PNG |
+        consts::MESSAGE_IMAGE_FORMAT_JPEG |
+        consts::MESSAGE_IMAGE_FORMAT_GIF |
+        consts::MESSAGE_IMAGE_FORMAT_WEBP => {},
+        _ => return Err(Error::InvalidImageFormat(format))
+    }
+    let len = read_u32(buf)? as usize;
+    if len > opts.limits.max_total_bytes {
+        return Err(Error::LimitExceeded(Limit::TotalBytes));
+    }
+    Ok((format, take_bytes(buf, len)?))
+}
+
+fn read_emo<I: Input>(buf: &mut I, opts: &DecodeOptions) -> Result<EmoView<I::Text, I::Bytes>> {
+    let emo_code = read_u8(buf)?;
+    let emo = match emo_code {
+        consts::MESSAGE_EMO_CODE_NOP => EmoView::Nop,
+        consts::MESSAGE_EMO_CODE_LAUGH => EmoView::Laugh,
+        consts::MESSAGE_EMO_CODE_CRY => EmoView::Cry,
+        consts::MESSAGE_EMO_CODE_CUSTOM => {
+            let id = read_text(buf, opts)?;
+            let image_type_code = read_u8(buf)?;
+            let image = match image_type_code {
+                consts::MESSAGE_TYPE_CODE_NOP => None,
+                consts::MESSAGE_TYPE_CODE_IMAGE => Some(read_image(buf, opts)?),
+                _ => return Err(Error::InvalidTypeCode(image_type_code))
+            };
+            EmoView::Custom(id, image)
+        },
+        _ => return Err(Error::InvalidEmoCode(emo_code))
+    };
+    Ok(emo)
+}
+
+// Decodes the envelope payload of the control message with type code `code`.
+// Bytes following the known fields are ignored.
+fn read_control<I: Input>(code: u8, payload: &mut I, opts: &DecodeOptions) -> Result<Control> {
+    let ctrl = match code {
+        consts::MESSAGE_TYPE_CODE_PING => Control::Ping,
+        consts::MESSAGE_TYPE_CODE_PONG => Control::Pong,
+        consts::MESSAGE_TYPE_CODE_ACK => Control::Ack(read_u64(payload)?),
+        consts::MESSAGE_TYPE_CODE_TYPING_START => Control::TypingStart,
+        consts::MESSAGE_TYPE_CODE_TYPING_STOP => Control::TypingStop,
+        consts::MESSAGE_TYPE_CODE_READ_RECEIPT => Control::ReadReceipt(read_u64(payload)?),
+        consts::MESSAGE_TYPE_CODE_CLOSE => Control::Close(read_u16(payload)?),
+        consts::MESSAGE_TYPE_CODE_ERROR => Control::Error {
+            code: read_u16(payload)?,
+            description: read_string(payload, opts)?,
+        },
+        _ => return Err(Error::InvalidTypeCode(code))
+    };
+    Ok(ctrl)
+}
+
+// Decodes the payload of a length-delimited envelope with `decode`.
+// Error positions are relative to the start of the envelope.
+fn decode_payload<I: Input, T, F>(buf: &mut I, opts: &DecodeOptions, decode: F) -> Result<T>
+    where F: FnOnce(&mut I) -> Result<T> {
+    let len = read_u32(buf)? as usize;
+    if len > opts.limits.max_total_bytes {
+        return Err(Error::LimitExceeded(Limit::TotalBytes));
+    }
+    let mut payload = take(buf, len)?;
+    decode(&mut payload).map_err(|e| e.at(consts::ENVELOPE_HEADER_LENGTH + len - payload.len()))
+}
+
+// Name of messages with type code `code` in error paths.
+pub(crate) fn type_name(code: u8) -> String {
+    let name = match code {
+        consts::MESSAGE_TYPE_CODE_NOP => "nop",
+        consts::MESSAGE_TYPE_CODE_PING => "ping",
+        consts::MESSAGE_TYPE_CODE_PONG => "pong",
+        consts::MESSAGE_TYPE_CODE_ACK => "ack",
+        consts::MESSAGE_TYPE_CODE_TYPING_START => "typing_start",
+        consts::MESSAGE_TYPE_CODE_TYPING_STOP => "typing_stop",
+        consts::MESSAGE_TYPE_CODE_READ_RECEIPT => "read_receipt",
+        consts::MESSAGE_TYPE_CODE_CLOSE => "close",
+        consts::MESSAGE_TYPE_CODE_ERROR => "error",
+        consts::MESSAGE_TYPE_CODE_TEXT => "text",
+        consts::MESSAGE_TYPE_CODE_RICH_TEXT => "rich_text",
+        consts::MESSAGE_TYPE_CODE_EMO => "emo",
+        consts::MESSAGE_TYPE_CODE_ATTACHMENT => "attachment",
+        consts::MESSAGE_TYPE_CODE_CHUNK => "chunk",
+        consts::MESSAGE_TYPE_CODE_LOCATION => "location",
+        consts::MESSAGE_TYPE_CODE_IMAGE => "image",
+        consts::MESSAGE_TYPE_CODE_VOICE => "voice",
+        consts::MESSAGE_TYPE_CODE_COMPOUND => "compound",
+        consts::MESSAGE_TYPE_CODE_BINARY => "binary",
+        _ => return format!("type {}", code)
+    };
+    String::from(name)
+}
+
+/// Errors are returned as `Error::Context`, holding the path to the failing message (e.g.
+/// `compound[3].text`) and the offset from the start of the outermost message.
 impl Decoder for Message {
-    fn decode_from(buf: &mut BytesMut) -> Result<Self> {
-        let type_code = buf[0];
-        buf.advance(1);
-        let msg = match type_code {
-            consts::MESSAGE_TYPE_CODE_NOP => Message::Nop,
-            consts::MESSAGE_TYPE_CODE_TEXT => {
-                let s = try!(String::decode_from(buf));
-                Message::Text(s)
-            },
-            consts::MESSAGE_TYPE_CODE_EMO => {
-                let s = try!(Emo::decode_from(buf));
-                Message::Emo(s)
-            },
-            consts::MESSAGE_TYPE_CODE_IMAGE => {
-                panic!("Not implemented yet");
-            },
-            consts::MESSAGE_TYPE_CODE_COMPOUND => {
-                let mut length = buf[0];
-                buf.advance(1);
-                let mut msgs = Vec::new();
-                while length == consts::COMPOUND_OVERFLOW_FLAG {
-                    for _ in 0..consts::COMPOUND_SLICE_MAX_LENGTH_S {
-                        msgs.push(try!(Self::decode_from(buf)));
-                    }
-                    length = buf[0];
-                    buf.advance(1);
+    fn decode_with(buf: &mut BytesMut, opts: &DecodeOptions) -> Result<Self> {
+        Message::decode_nested(buf, &DecodeContext::new(opts, buf))
+    }
+
+    fn decode_nested(buf: &mut BytesMut, ctx: &DecodeContext) -> Result<Self> {
+        decode_nested_message(buf, ctx.opts, ctx.nesting).map(Message::from)
+    }
+}
+
+// Where a message being decoded sits in the outermost one.
+#[derive(Clone, Copy)]
+pub(crate) struct Nesting {
+    // Number of enclosing compound messages
+    pub(crate) depth: usize,
+    // Length `buf` had at the start of the outermost message
+    pub(crate) start: usize,
+}
+
+pub(crate) type View<I> = MessageView<<I as Input>::Text, <I as Input>::Bytes>;
+
+pub(crate) fn decode_nested_message<I: Input>(buf: &mut I, opts: &DecodeOptions,
+                                              nesting: Nesting) -> Result<View<I>> {
+    let type_code = read_u8(buf).map_err(|e| e.at(0))?;
+    let start = buf.len();
+    decode_message(type_code, buf, opts, nesting)
+        .and_then(|msg| {
+            if nesting.start - buf.len() > opts.limits.max_total_bytes {
+                return Err(Error::LimitExceeded(Limit::TotalBytes));
+            }
+            Ok(msg)
+        })
+        .map_err(|e| e.at(start - buf.len()).within(type_name(type_code), 1))
+}
+
+// Decodes everything of a message but its type code.
+// Error positions are relative to the end of the type code.
+fn decode_message<I: Input>(type_code: u8, buf: &mut I, opts: &DecodeOptions,
+                            nesting: Nesting) -> Result<View<I>> {
+    let start = buf.len();
+    let msg = match type_code {
+        consts::MESSAGE_TYPE_CODE_NOP => MessageView::Nop,
+        consts::MESSAGE_TYPE_CODE_PING |
+        consts::MESSAGE_TYPE_CODE_PONG |
+        consts::MESSAGE_TYPE_CODE_ACK |
+        consts::MESSAGE_TYPE_CODE_TYPING_START |
+        consts::MESSAGE_TYPE_CODE_TYPING_STOP |
+        consts::MESSAGE_TYPE_CODE_READ_RECEIPT |
+        consts::MESSAGE_TYPE_CODE_CLOSE |
+        consts::MESSAGE_TYPE_CODE_ERROR => {
+            MessageView::Control(decode_payload(buf, opts, |payload| read_control(type_code, payload, opts))?)
+        },
+        consts::MESSAGE_TYPE_CODE_TEXT => MessageView::Text(read_text(buf, opts)?),
+        consts::MESSAGE_TYPE_CODE_RICH_TEXT => {
+            let (s, spans) = decode_payload(buf, opts, |payload| {
+                let s = read_text(payload, opts)?;
+                let spans = read_spans(payload, opts)?;
+                check_spans(&s, &spans)?;
+                Ok((s, spans))
+            })?;
+            MessageView::RichText(s, spans)
+        },
+        consts::MESSAGE_TYPE_CODE_EMO => MessageView::Emo(read_emo(buf, opts)?),
+        consts::MESSAGE_TYPE_CODE_ATTACHMENT => {
+            MessageView::Attachment(decode_payload(buf, opts, |payload| read_attachment(payload, opts))?)
+        },
+        consts::MESSAGE_TYPE_CODE_CHUNK => {
+            MessageView::Chunk(decode_payload(buf, opts, |payload| read_chunk(payload, opts))?)
+        },
+        consts::MESSAGE_TYPE_CODE_LOCATION => {
+            MessageView::Location(decode_payload(buf, opts, |payload| read_location(payload, opts))?)
+        },
+        consts::MESSAGE_TYPE_CODE_IMAGE => {
+            let (format, data) = read_image(buf, opts)?;
+            MessageView::Image(format, data)
+        },
+        consts::MESSAGE_TYPE_CODE_VOICE => decode_payload(buf, opts, |payload| {
+            let codec = read_u8(payload)?;
+            match codec {
+                consts::MESSAGE_VOICE_CODEC_OPUS |
+                consts::MESSAGE_VOICE_CODEC_AAC => {},
+                _ => return Err(Error::InvalidVoiceCodec(codec))
+            }
+            Ok(MessageView::Voice {
+                codec,
+                duration: read_u32(payload)?,
+                waveform: read_option(payload, read_byte_vec)?,
+                data: read_byte_vec(payload)?,
+            })
+        })?,
+        consts::MESSAGE_TYPE_CODE_COMPOUND => {
+            if nesting.depth >= opts.limits.max_depth {
+                return Err(Error::LimitExceeded(Limit::Depth));
+            }
+            let nested = Nesting { depth: nesting.depth + 1, ..nesting };
+            let mut length = read_u8(buf)?;
+            let mut msgs = Vec::new();
+            while length == consts::COMPOUND_OVERFLOW_FLAG {
+                for _ in 0..consts::COMPOUND_SLICE_MAX_LENGTH_S {
+                    decode_nested(buf, opts, start, nested, &mut msgs)?;
                 }
-                for _ in 0..length {
-                    let msg = Self::decode_from(buf);
-                    msgs.push(try!(msg));
+                length = read_u8(buf)?;
+            }
+            for _ in 0..length {
+                decode_nested(buf, opts, start, nested, &mut msgs)?;
+            }
+            MessageView::Compound(msgs)
+        },
+        consts::MESSAGE_TYPE_CODE_BINARY => decode_payload(buf, opts, |payload| {
+            let len = payload.len();
+            Ok(MessageView::Binary(payload.split_bytes(len)))
+        })?,
+        _ => decode_payload(buf, opts, |payload| match opts.registry {
+            Some(ref registry) if registry.has_type(type_code) => {
+                // Registered types count as a level of nesting, as they may hold messages
+                if nesting.depth >= opts.limits.max_depth {
+                    return Err(Error::LimitExceeded(Limit::Depth));
                 }
-                Message::Compound(msgs)
+                // The payload follows the type code and payload length in the outermost message
+                let consumed = nesting.start - start + consts::ENVELOPE_HEADER_LENGTH;
+                let nested = Nesting { depth: nesting.depth + 1, start: consumed + payload.len() };
+                let ctx = DecodeContext { opts, nesting: nested };
+                let obj = payload.with_bytes_mut(|bytes| registry.decode_object(type_code, bytes, &ctx))?;
+                Ok(MessageView::Object(obj))
             },
-            _ => return Err(Error::InvalidTypeCode(type_code))
-        };
-        return Ok(msg);
+            Some(ref registry) if registry.contains(type_code) => {
+                let len = payload.len();
+                Ok(MessageView::Extension { code: type_code, payload: payload.split_to(len).into_bytes() })
+            },
+            _ => {
+                let len = payload.len();
+                Ok(MessageView::Unknown(type_code, payload.split_to(len).into_bytes()))
+            }
+        })?
+    };
+    Ok(msg)
+}
+
+// Decodes the next nested message of a compound message and appends it to `msgs`.
+// `start` is the length `buf` had at the start of the compound message's body.
+fn decode_nested<I: Input>(buf: &mut I, opts: &DecodeOptions, start: usize, nesting: Nesting,
+                           msgs: &mut Vec<View<I>>) -> Result<()> {
+    if msgs.len() >= opts.limits.max_children {
+        return Err(Error::LimitExceeded(Limit::Children));
     }
+    let offset = start - buf.len();
+    let msg = decode_nested_message(buf, opts, nesting)
+        .map_err(|e| e.within(format!("[{}]", msgs.len()), offset))?;
+    msgs.push(msg);
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use bytes::{BytesMut};
-    use super::{Message, Emo, Decoder};
+    use super::consts;
+    use super::{Message, Control, Emo, Envelope, ProtocolVersion, Span, Style, Attachment,
+                Content, Chunk, Location, Decoder, DecodeOptions, DecodeLimits};
+    use super::{capacity, read_varint};
+    use super::super::encoder::Encoder;
+    use super::super::error::{Error, Limit};
+    use proptest::prelude::*;
+
+    fn small_limits() -> DecodeOptions {
+        DecodeOptions {
+            limits: DecodeLimits {
+                max_depth: 4,
+                max_total_bytes: 64,
+                max_children: 8,
+                max_string_length: 16,
+            },
+            ..DecodeOptions::default()
+        }
+    }
+
+    fn expect_limit(result: super::Result<Message>, limit: Limit) {
+        match result.map_err(Error::into_kind) {
+            Err(Error::LimitExceeded(l)) => assert_eq!(l, limit),
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    // `depth` compound messages nested in each other, around a Nop
+    fn nested(depth: usize) -> Vec<u8> {
+        let mut bytes = b"\xfa\x01".repeat(depth);
+        bytes.push(0);
+        bytes
+    }
 
     #[test]
     fn decode_text() {
@@ -132,6 +920,46 @@ mod tests {
                 Message::Emo(Emo::Cry)
             );
         }
+        {
+            let mut bm = BytesMut::from(&b"\x82\xf0\x00\x09pack/wave\x00"[..]);
+            let msg = Message::decode_from(&mut bm);
+            assert_eq!(
+                msg.unwrap(),
+                Message::Emo(Emo::Custom(String::from("pack/wave"), None))
+            );
+        }
+        {
+            let mut bm = BytesMut::from(
+                &b"\x82\xf0\x00\x09pack/wave\x8c\x00\x00\x00\x00\x04\x89PNG"[..]
+            );
+            let msg = Message::decode_from(&mut bm);
+            assert_eq!(
+                msg.unwrap(),
+                Message::Emo(Emo::Custom(
+                    String::from("pack/wave"),
+                    Some((consts::MESSAGE_IMAGE_FORMAT_PNG, b"\x89PNG".to_vec()))
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn decode_emo_invalid() {
+        {
+            let mut bm = BytesMut::from(&b"\x82\x0a"[..]);
+            match Message::decode_from(&mut bm).map_err(Error::into_kind) {
+                Err(Error::InvalidEmoCode(0x0a)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+        {
+            // The inline image slot of a custom emo only accepts Nop or Image
+            let mut bm = BytesMut::from(&b"\x82\xf0\x00\x01x\x80\x00\x00"[..]);
+            match Message::decode_from(&mut bm).map_err(Error::into_kind) {
+                Err(Error::InvalidTypeCode(0x80)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
     }
 
     #[test]
@@ -154,4 +982,683 @@ mod tests {
             ])
         );
     }
+
+    #[test]
+    fn decode_image() {
+        let mut bm = BytesMut::from(&b"\x8c\x03\x00\x00\x00\x04RIFF"[..]);
+        let msg = Message::decode_from(&mut bm);
+        assert_eq!(
+            msg.unwrap(),
+            Message::Image(consts::MESSAGE_IMAGE_FORMAT_WEBP, b"RIFF".to_vec())
+        );
+        assert!(bm.is_empty());
+    }
+
+    #[test]
+    fn decode_image_invalid_format() {
+        let mut bm = BytesMut::from(&b"\x8c\x09\x00\x00\x00\x00"[..]);
+        match Message::decode_from(&mut bm).map_err(Error::into_kind) {
+            Err(Error::InvalidImageFormat(0x09)) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn roundtrip_image() {
+        for &format in &[consts::MESSAGE_IMAGE_FORMAT_PNG,
+                         consts::MESSAGE_IMAGE_FORMAT_JPEG,
+                         consts::MESSAGE_IMAGE_FORMAT_GIF,
+                         consts::MESSAGE_IMAGE_FORMAT_WEBP] {
+            for &len in &[0, 1, 0x10000, 5 * 1024 * 1024] {
+                let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+                let msg = Message::Image(format, data);
+                let mut buf = BytesMut::new();
+                msg.encode_into(&mut buf);
+                assert_eq!(buf.len(), 1 + 1 + 4 + len);
+                assert_eq!(Message::decode_from(&mut buf).unwrap(), msg);
+                assert!(buf.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn decode_control() {
+        let ctrls = vec![
+            Control::Ping,
+            Control::Pong,
+            Control::Ack(u64::MAX),
+            Control::TypingStart,
+            Control::TypingStop,
+            Control::ReadReceipt(42),
+            Control::Close(consts::CLOSE_REASON_NORMAL),
+            Control::Error { code: 404, description: String::from("没有找到") },
+        ];
+        let msg = Message::Compound(ctrls.into_iter().map(Message::Control).collect());
+        let mut bm = BytesMut::new();
+        msg.encode_into(&mut bm);
+        assert_eq!(Message::decode_from(&mut bm).unwrap(), msg);
+        assert!(bm.is_empty());
+    }
+
+    #[test]
+    fn decode_control_extended() {
+        // Fields appended by later versions are skipped
+        let mut bm = BytesMut::from(&b"\x07\x00\x00\x00\x04\x00\x02\xab\xcd\x01\x00\x00\x00\x00"[..]);
+        assert_eq!(
+            Message::decode_from(&mut bm).unwrap(),
+            Message::Control(Control::Close(consts::CLOSE_REASON_PROTOCOL_ERROR))
+        );
+        assert_eq!(Message::decode_from(&mut bm).unwrap(), Message::Control(Control::Ping));
+        assert!(bm.is_empty());
+    }
+
+    #[test]
+    fn decode_control_truncated() {
+        // The envelope is complete, but the payload is too short for a message id
+        let mut bm = BytesMut::from(&b"\x03\x00\x00\x00\x02\x00\x01"[..]);
+        match Message::decode_from(&mut bm).map_err(Error::into_kind) {
+            Err(Error::UnexpectedEof(6)) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn roundtrip_envelope() {
+        for &timestamp in &[0, 0x7f, 0x80, 1_500_000_000_000, u64::MAX] {
+            let envelope = Envelope {
+                id: 10,
+                sender: 20,
+                conversation: 30,
+                timestamp,
+                reply_to: if timestamp % 2 == 0 { None } else { Some(9) },
+                message: Message::Compound(vec![Message::Text(String::from("回复"))]),
+            };
+            let mut bm = BytesMut::new();
+            envelope.encode_into(&mut bm);
+            assert_eq!(Envelope::decode_from(&mut bm).unwrap(), envelope);
+            assert!(bm.is_empty());
+        }
+    }
+
+    #[test]
+    fn decode_varint_invalid() {
+        {
+            let mut bm = BytesMut::from(&b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\x02"[..]);
+            match read_varint(&mut bm) {
+                Err(Error::InvalidVarint) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+        {
+            let mut bm = BytesMut::from(&b"\x80\x80"[..]);
+            match read_varint(&mut bm) {
+                Err(Error::UnexpectedEof(1)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+    }
+
+    #[test]
+    fn roundtrip_rich_text() {
+        let text = String::from("加粗 *italic* `code` ~~del~~ link @alice");
+        let styles = vec![
+            Style::Bold,
+            Style::Italic,
+            Style::Code,
+            Style::Strikethrough,
+            Style::Link(String::from("https://example.com/链接")),
+            Style::Mention(1),
+        ];
+        let mut spans: Vec<Span> = styles.into_iter().enumerate().map(|(i, style)| {
+            Span { start: i as u32 * 6, end: i as u32 * 6 + 6, style }
+        }).collect();
+        spans.push(Span { start: 0, end: text.len() as u32, style: Style::Italic });
+        spans.push(Span { start: 3, end: 3, style: Style::Bold });
+        let msg = Message::RichText(text, spans);
+        let mut bm = BytesMut::new();
+        msg.encode_into(&mut bm);
+        assert_eq!(Message::decode_from(&mut bm).unwrap(), msg);
+        assert!(bm.is_empty());
+    }
+
+    #[test]
+    fn decode_rich_text_invalid() {
+        let cases = vec![
+            // Past the end of the text
+            (Span { start: 4, end: 9, style: Style::Bold }, (4, 9)),
+            // Reversed
+            (Span { start: 2, end: 1, style: Style::Bold }, (2, 1)),
+            // Inside a multi-byte character
+            (Span { start: 0, end: 3, style: Style::Bold }, (0, 3)),
+        ];
+        for (span, (start, end)) in cases {
+            let mut bm = BytesMut::new();
+            Message::RichText(String::from("ab测试"), vec![span]).encode_into(&mut bm);
+            match Message::decode_from(&mut bm).map_err(Error::into_kind) {
+                Err(Error::InvalidSpan(s, e)) => assert_eq!((s, e), (start, end)),
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+        let mut bm = BytesMut::from(&b"\x81\x00\x00\x00\x10\x00\x01a\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x01\x09"[..]);
+        match Message::decode_from(&mut bm).map_err(Error::into_kind) {
+            Err(Error::InvalidStyleCode(0x09)) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn roundtrip_voice() {
+        let msg = Message::Compound(vec![
+            Message::Voice {
+                codec: consts::MESSAGE_VOICE_CODEC_AAC,
+                duration: 60000,
+                waveform: Some((0..=255).collect()),
+                data: vec![0xaa; 100000],
+            },
+            Message::Voice {
+                codec: consts::MESSAGE_VOICE_CODEC_OPUS,
+                duration: 0,
+                waveform: None,
+                data: vec![],
+            },
+        ]);
+        let mut bm = BytesMut::new();
+        msg.encode_into(&mut bm);
+        assert_eq!(Message::decode_from(&mut bm).unwrap(), msg);
+        assert!(bm.is_empty());
+    }
+
+    #[test]
+    fn decode_voice_invalid_codec() {
+        let mut bm = BytesMut::from(&b"\x8d\x00\x00\x00\x0a\x05\x00\x00\x00\x01\x00\x00\x00\x00\x00"[..]);
+        match Message::decode_from(&mut bm).map_err(Error::into_kind) {
+            Err(Error::InvalidVoiceCodec(0x05)) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn roundtrip_location() {
+        let msg = Message::Compound(vec![
+            Message::Location(Location {
+                latitude: 35_681_236,
+                longitude: 139_767_125,
+                accuracy: None,
+                name: Some(String::from("東京駅")),
+                live_until: None,
+            }),
+            Message::Location(Location {
+                latitude: -90_000_000,
+                longitude: 180_000_000,
+                accuracy: Some(5),
+                name: None,
+                live_until: Some(1_500_000_900_000),
+            }),
+        ]);
+        let mut bm = BytesMut::new();
+        msg.encode_into(&mut bm);
+        assert_eq!(Message::decode_from(&mut bm).unwrap(), msg);
+        assert!(bm.is_empty());
+    }
+
+    #[test]
+    fn decode_location_invalid() {
+        for &(latitude, longitude) in &[(90_000_001, 0), (0, -180_000_001), (i32::MIN, i32::MAX)] {
+            let mut bm = BytesMut::new();
+            Message::Location(Location {
+                latitude, longitude, accuracy: None, name: None, live_until: None
+            }).encode_into(&mut bm);
+            match Message::decode_from(&mut bm).map_err(Error::into_kind) {
+                Err(Error::InvalidCoordinates(lat, lon)) => assert_eq!((lat, lon), (latitude, longitude)),
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+    }
+
+    #[test]
+    fn roundtrip_attachment() {
+        let msg = Message::Compound(vec![
+            Message::Attachment(Attachment {
+                filename: String::from("照片.raw"),
+                mime_type: String::from("application/octet-stream"),
+                size: 200000,
+                sha256: [7; 32],
+                content: Content::Inline(vec![0xee; 200000]),
+            }),
+            Message::Attachment(Attachment {
+                filename: String::from("big.bin"),
+                mime_type: String::from("application/octet-stream"),
+                size: 1 << 40,
+                sha256: [8; 32],
+                content: Content::Chunked { transfer: 3, count: 1 << 20 },
+            }),
+            Message::Chunk(Chunk { transfer: 3, index: 0, data: vec![0x11; 65534] }),
+            Message::Chunk(Chunk { transfer: 3, index: 1, data: vec![] }),
+        ]);
+        let mut bm = BytesMut::new();
+        msg.encode_into(&mut bm);
+        assert_eq!(Message::decode_from(&mut bm).unwrap(), msg);
+        assert!(bm.is_empty());
+    }
+
+    #[test]
+    fn decode_unknown() {
+        let bytes = b"\xfa\x03\x80\x00\x02hi\x10\x00\x00\x00\x03\xfa\x01\x00\x8c\x00\x00\x00\x00\x00";
+        let mut bm = BytesMut::from(&bytes[..]);
+        let msg = Message::decode_from(&mut bm).unwrap();
+        assert!(bm.is_empty());
+        assert_eq!(
+            msg,
+            Message::Compound(vec![
+                Message::Text(String::from("hi")),
+                // The payload is not interpreted, even though it looks like a compound
+                Message::Unknown(0x10, b"\xfa\x01\x00".to_vec()),
+                Message::Image(consts::MESSAGE_IMAGE_FORMAT_PNG, vec![])
+            ])
+        );
+        let mut reencoded = BytesMut::new();
+        msg.encode_into(&mut reencoded);
+        assert_eq!(&reencoded[..], &bytes[..]);
+    }
+
+    #[test]
+    fn decode_truncated() {
+        let mut full = BytesMut::new();
+        Message::Compound(vec![
+            Message::Nop,
+            Message::Text(String::from("ITRE解码测试")),
+            Message::Emo(Emo::Laugh),
+            Message::Emo(Emo::Custom(
+                String::from("pack/wave"),
+                Some((consts::MESSAGE_IMAGE_FORMAT_PNG, b"\x89PNG".to_vec()))
+            )),
+            Message::Image(consts::MESSAGE_IMAGE_FORMAT_JPEG, vec![0xff; 16]),
+            Message::Control(Control::Error { code: 1, description: String::from("e") }),
+            Message::Binary(b"raw".to_vec()),
+            Message::Unknown(0xfc, b"new".to_vec()),
+            Message::Compound(vec![Message::Emo(Emo::Cry)])
+        ]).encode_into(&mut full);
+        for len in 0..full.len() {
+            let mut bm = BytesMut::from(&full[..len]);
+            match Message::decode_from(&mut bm).map_err(Error::into_kind) {
+                Err(Error::UnexpectedEof(needed)) => assert!(needed > 0),
+                other => panic!("prefix of {} bytes: unexpected result: {:?}", len, other)
+            }
+        }
+    }
+
+    #[test]
+    fn decode_truncated_needed() {
+        {
+            let mut bm = BytesMut::new();
+            match Message::decode_from(&mut bm).map_err(Error::into_kind) {
+                Err(Error::UnexpectedEof(1)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+        {
+            let mut bm = BytesMut::from(&b"\x80\x00\x05Hel"[..]);
+            match Message::decode_from(&mut bm).map_err(Error::into_kind) {
+                Err(Error::UnexpectedEof(2)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+        {
+            let mut bm = BytesMut::from(&b"\x8c\x00\x00\x10"[..]);
+            match String::decode_from(&mut bm) {
+                Err(Error::UnexpectedEof(0x8bfe)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+        {
+            let mut bm = BytesMut::new();
+            match Emo::decode_from(&mut bm) {
+                Err(Error::UnexpectedEof(1)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+    }
+
+    #[test]
+    fn decode_invalid_utf8() {
+        let mut bm = BytesMut::from(&b"\xfa\x01\x80\x00\x05ab\xffcd"[..]);
+        match Message::decode_from(&mut bm).map_err(Error::into_kind) {
+            Err(Error::InvalidUtf8(2)) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn decode_error_context() {
+        let mut bm = BytesMut::from(&b"\xfa\x04\x00\x00\x80\x00\x01a\x80\x00\x02b\xff"[..]);
+        let err = Message::decode_from(&mut bm).unwrap_err();
+        {
+            let ctx = err.context().unwrap();
+            assert_eq!(ctx.path, vec!["compound", "[3]", "text"]);
+            assert_eq!(ctx.offset, 13);
+        }
+        match *err.kind() {
+            Error::InvalidUtf8(1) => {},
+            ref other => panic!("unexpected error: {:?}", other)
+        }
+        assert_eq!(err.to_string(), "compound[3].text at offset 13: invalid utf-8 after 1 valid bytes");
+
+        let envelope = Envelope {
+            id: 1,
+            sender: 2,
+            conversation: 3,
+            timestamp: 0,
+            reply_to: None,
+            message: Message::Compound(vec![Message::Location(Location {
+                latitude: 0,
+                longitude: 200_000_000,
+                accuracy: None,
+                name: None,
+                live_until: None,
+            })]),
+        };
+        let mut bm = BytesMut::new();
+        envelope.encode_into(&mut bm);
+        assert_eq!(
+            Envelope::decode_from(&mut bm).unwrap_err().to_string(),
+            "message.compound[0].location at offset 41: invalid coordinates (0, 200000000)"
+        );
+
+        let mut bm = BytesMut::from(&b"\x00\x00"[..]);
+        assert_eq!(
+            Envelope::decode_from(&mut bm).unwrap_err().to_string(),
+            "at offset 0: unexpected end of input, 6 more bytes needed"
+        );
+    }
+
+    #[test]
+    fn decode_limit_depth() {
+        let mut bm = BytesMut::from(&nested(32)[..]);
+        Message::decode_from(&mut bm).unwrap();
+        let mut bm = BytesMut::from(&nested(33)[..]);
+        expect_limit(Message::decode_from(&mut bm), Limit::Depth);
+        // Would overflow the stack without the limit
+        let mut bm = BytesMut::from(&nested(1_000_000)[..]);
+        expect_limit(Message::decode_from(&mut bm), Limit::Depth);
+        let mut bm = BytesMut::from(&nested(5)[..]);
+        expect_limit(Message::decode_with(&mut bm, &small_limits()), Limit::Depth);
+    }
+
+    #[test]
+    fn decode_limit_children() {
+        let mut bm = BytesMut::from(&b"\xfa\x08\x00\x00\x00\x00\x00\x00\x00\x00"[..]);
+        Message::decode_with(&mut bm, &small_limits()).unwrap();
+        let mut bm = BytesMut::from(&b"\xfa\x09\x00\x00\x00\x00\x00\x00\x00\x00\x00"[..]);
+        expect_limit(Message::decode_with(&mut bm, &small_limits()), Limit::Children);
+    }
+
+    #[test]
+    fn decode_limit_string_length() {
+        let mut bm = BytesMut::new();
+        Message::Text("x".repeat(16)).encode_into(&mut bm);
+        Message::decode_with(&mut bm, &small_limits()).unwrap();
+        let mut bm = BytesMut::new();
+        Message::Text("x".repeat(17)).encode_into(&mut bm);
+        expect_limit(Message::decode_with(&mut bm, &small_limits()), Limit::StringLength);
+        // Fails on the declared length, before the text arrives
+        let mut bm = BytesMut::from(&b"\x80\xff\xff"[..]);
+        expect_limit(Message::decode_with(&mut bm, &small_limits()), Limit::StringLength);
+        // Applies to strings nested in other messages, too
+        let mut bm = BytesMut::new();
+        Message::Emo(Emo::Custom("x".repeat(17), None)).encode_into(&mut bm);
+        expect_limit(Message::decode_with(&mut bm, &small_limits()), Limit::StringLength);
+    }
+
+    #[test]
+    fn decode_limit_total_bytes() {
+        // Declared lengths are checked before the data arrives
+        let mut bm = BytesMut::from(&b"\x8c\x00\xff\xff\xff\xff"[..]);
+        expect_limit(Message::decode_from(&mut bm), Limit::TotalBytes);
+        let mut bm = BytesMut::from(&b"\x10\x00\x00\x00\x41"[..]);
+        expect_limit(Message::decode_with(&mut bm, &small_limits()), Limit::TotalBytes);
+
+        let msg = Message::Compound((0..4).map(|_| Message::Text("x".repeat(12))).collect());
+        let mut bm = BytesMut::new();
+        msg.encode_into(&mut bm);
+        assert_eq!(bm.len(), 2 + 4 * 15);
+        assert_eq!(Message::decode_with(&mut bm, &small_limits()).unwrap(), msg);
+        let msg = Message::Compound(vec![
+            Message::Compound((0..4).map(|_| Message::Text("x".repeat(12))).collect()),
+            Message::Nop
+        ]);
+        let mut bm = BytesMut::new();
+        msg.encode_into(&mut bm);
+        expect_limit(Message::decode_with(&mut bm, &small_limits()), Limit::TotalBytes);
+    }
+
+    #[test]
+    fn decode_vec_capacity() {
+        // A huge announced count allocates no more than the bytes left in the input
+        let mut bm = BytesMut::from(&b"\xff\xff\xff\xff"[..]);
+        bm.extend_from_slice(&[0; 1 << 20]);
+        assert!(capacity::<Message>(u32::MAX, bm.len() - 4, &DecodeOptions::default()) *
+                    std::mem::size_of::<Message>() <= 1 << 20);
+        match Vec::<Message>::decode_from(&mut bm) {
+            Err(Error::LimitExceeded(Limit::Children)) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+        // Counts are checked against the input before decoding any item
+        let mut bm = BytesMut::from(&b"\x00\x01\x00\x00"[..]);
+        bm.extend_from_slice(&[0; 100]);
+        match Vec::<Message>::decode_from(&mut bm) {
+            Err(Error::UnexpectedEof(65436)) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+        // Nor than `max_total_bytes`
+        assert_eq!(capacity::<u64>(1000, 1 << 20, &small_limits()), 64 / 8);
+        assert_eq!(capacity::<u8>(3, 1 << 20, &small_limits()), 3);
+    }
+
+    // Encodings of a few messages of every kind, to be mangled by the tests below
+    fn seeds() -> Vec<Vec<u8>> {
+        let msgs = [
+            Message::Compound(vec![
+                Message::Text(String::from("种子")),
+                Message::Compound(vec![Message::Emo(Emo::Laugh), Message::Nop]),
+                Message::Image(consts::MESSAGE_IMAGE_FORMAT_GIF, vec![1, 2, 3]),
+            ]),
+            Message::RichText(String::from("bold"), vec![Span { start: 0, end: 4, style: Style::Bold }]),
+            Message::Control(Control::Error { code: 1, description: String::from("e") }),
+            Message::Attachment(Attachment {
+                filename: String::from("a"),
+                mime_type: String::from("b"),
+                size: 1,
+                sha256: [0; 32],
+                content: Content::Chunked { transfer: 1, count: 1 },
+            }),
+            Message::Voice { codec: 0, duration: 1, waveform: Some(vec![1]), data: vec![2] },
+        ];
+        msgs.iter().map(|msg| {
+            let mut bm = BytesMut::new();
+            msg.encode_into(&mut bm);
+            bm.to_vec()
+        }).collect()
+    }
+
+    proptest! {
+        #[test]
+        fn decode_arbitrary_bytes(bytes in proptest::collection::vec(any::<u8>(), 0..1024)) {
+            let mut bm = BytesMut::from(&bytes[..]);
+            let _ = Message::decode_from(&mut bm);
+            let mut bm = BytesMut::from(&bytes[..]);
+            let _ = Message::decode_with(&mut bm, &small_limits());
+        }
+
+        #[test]
+        fn decode_mangled(seed in 0..5usize,
+                          edits in proptest::collection::vec((any::<usize>(), any::<u8>()), 1..8),
+                          repeat in 0..2000usize) {
+            let mut bytes = seeds().swap_remove(seed);
+            for (pos, byte) in edits {
+                let pos = pos % bytes.len();
+                bytes[pos] = byte;
+            }
+            // Blow a prefix up into deep or wide nesting
+            let prefix = bytes[..bytes.len().min(2)].to_vec();
+            for _ in 0..repeat {
+                bytes.splice(0..0, prefix.iter().cloned());
+            }
+            let mut bm = BytesMut::from(&bytes[..]);
+            if let Ok(msg) = Message::decode_with(&mut bm, &small_limits()) {
+                let mut encoded = BytesMut::new();
+                msg.encode_into(&mut encoded);
+                prop_assert!(encoded.len() <= 64);
+            }
+            let mut bm = BytesMut::from(&bytes[..]);
+            let _ = Message::decode_from(&mut bm);
+        }
+    }
+
+    #[test]
+    fn decode_invalid_utf8_lossy() {
+        let opts = DecodeOptions { lossy_utf8: true, ..DecodeOptions::default() };
+        let mut bm = BytesMut::from(&b"\xfa\x01\x80\x00\x05ab\xffcd"[..]);
+        let msg = Message::decode_with(&mut bm, &opts);
+        assert_eq!(
+            msg.unwrap(),
+            Message::Compound(vec![Message::Text(String::from("ab\u{fffd}cd"))])
+        );
+    }
+
+    #[test]
+    fn roundtrip_compound() {
+        for &len in &[0, 1, 254, 255, 508, 509, 10000] {
+            let msg = Message::Compound(
+                (0..len).map(|i| match i % 3 {
+                    0 => Message::Text(i.to_string()),
+                    1 => Message::Emo(Emo::Laugh),
+                    _ => Message::Compound(vec![Message::Nop])
+                }).collect()
+            );
+            let mut buf = BytesMut::new();
+            msg.encode_into(&mut buf);
+            assert_eq!(Message::decode_from(&mut buf).unwrap(), msg);
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn roundtrip_long_text() {
+        // 3-byte characters put a character boundary only on every third byte, so most
+        // slice boundaries fall inside a character. Shift the text to hit every alignment.
+        for prefix in &["", "a", "ab"] {
+            let text = format!("{}{}", prefix, "编码测试".repeat(200 * 1024 / 12));
+            assert!(text.len() > 3 * consts::TEXT_SLICE_MAX_LENGTH_S);
+            let msg = Message::Text(text);
+            let mut buf = BytesMut::new();
+            msg.encode_into(&mut buf);
+            assert_eq!(Message::decode_from(&mut buf).unwrap(), msg);
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn decode_invalid_utf8_across_slices() {
+        // The first slice ends inside the last "测", which is fine once the slices are joined
+        let mut text = "测".repeat(consts::TEXT_SLICE_MAX_LENGTH_S / 3 + 1).into_bytes();
+        text.push(0xff);
+        let mut bm = BytesMut::from(&b"\x80\xff\xff"[..]);
+        bm.extend_from_slice(&text[..consts::TEXT_SLICE_MAX_LENGTH_S]);
+        bm.extend_from_slice(&[0x00, 0x02]);
+        bm.extend_from_slice(&text[consts::TEXT_SLICE_MAX_LENGTH_S..]);
+        match Message::decode_from(&mut bm).map_err(Error::into_kind) {
+            Err(Error::InvalidUtf8(offset)) => assert_eq!(offset, text.len() - 1),
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn decode_frame_header() {
+        let mut bm = BytesMut::from(&b"ITRE\x01\x80\x00\x02Hi"[..]);
+        assert_eq!(ProtocolVersion::decode_from(&mut bm).unwrap(), ProtocolVersion::V0_1);
+        assert_eq!(
+            Message::decode_from(&mut bm).unwrap(),
+            Message::Text(String::from("Hi"))
+        );
+    }
+
+    #[test]
+    fn decode_frame_header_invalid() {
+        {
+            let mut bm = BytesMut::from(&b"ITRF\x01"[..]);
+            match ProtocolVersion::decode_from(&mut bm) {
+                Err(Error::InvalidFrameMagic(ref magic)) if magic == b"ITRF" => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+        {
+            let mut bm = BytesMut::from(&b"ITRE\x02"[..]);
+            match ProtocolVersion::decode_from(&mut bm) {
+                Err(Error::UnsupportedVersion(2)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+    }
+
+    #[test]
+    fn decode_frame_header_version_range() {
+        let opts = DecodeOptions {
+            versions: ProtocolVersion(1)..=ProtocolVersion(3),
+            ..DecodeOptions::default()
+        };
+        for version in 0..5 {
+            let mut bm = BytesMut::from(&b"ITRE"[..]);
+            bm.extend_from_slice(&[version]);
+            match ProtocolVersion::decode_with(&mut bm, &opts) {
+                Ok(v) => assert!((1..=3).contains(&version) && v == ProtocolVersion(version)),
+                Err(Error::UnsupportedVersion(v)) => assert!(v == version && !(1..=3).contains(&v)),
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+    }
+
+    #[test]
+    fn decode_primitives() {
+        let mut bm = BytesMut::from(&b"\
+        \x12\
+        \x12\x34\
+        \xff\xff\xff\xfe\
+        \x01\
+        \x00\x00\x00\x02\x00\x01\x00\x02\
+        \x01\x07\
+        \x00"[..]);
+        assert_eq!(u8::decode_from(&mut bm).unwrap(), 0x12);
+        assert_eq!(u16::decode_from(&mut bm).unwrap(), 0x1234);
+        assert_eq!(i32::decode_from(&mut bm).unwrap(), -2);
+        assert!(bool::decode_from(&mut bm).unwrap());
+        assert_eq!(Vec::<u16>::decode_from(&mut bm).unwrap(), vec![1, 2]);
+        assert_eq!(Option::<i8>::decode_from(&mut bm).unwrap(), Some(7));
+        assert_eq!(Option::<u64>::decode_from(&mut bm).unwrap(), None);
+        assert!(bm.is_empty());
+    }
+
+    #[test]
+    fn decode_primitives_invalid() {
+        {
+            let mut bm = BytesMut::from(&b"\x02"[..]);
+            match bool::decode_from(&mut bm) {
+                Err(Error::InvalidFlag(2)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+        {
+            let mut bm = BytesMut::from(&b"\x00\x01"[..]);
+            match u32::decode_from(&mut bm) {
+                Err(Error::UnexpectedEof(2)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+        {
+            let mut bm = BytesMut::from(&b"\x00\x00\x00\x03\x00"[..]);
+            match Vec::<u8>::decode_from(&mut bm) {
+                Err(Error::UnexpectedEof(2)) => {},
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+    }
 }
@@ -1,24 +1,273 @@
-use bytes::{BytesMut, BufMut};
-use byteorder::{BigEndian};
+use std;
+use bytes::{Bytes, BytesMut, BufMut};
+use byteorder::{ByteOrder, BigEndian};
 use super::consts;
-use super::{Message, Emo};
+use super::{Message, Control, Emo, Envelope, ProtocolVersion, Span, Style, Attachment, Content,
+            Chunk, Location};
 
 pub trait Encoder {
+    /// Appends the encoding of `self` to `buf`.
+    ///
+    /// Panics if a length written in 4 bytes, e.g. of an image or a payload, exceeds 2^32-1.
     fn encode_into(&self, buf: &mut BytesMut);
+
+    /// Exact number of bytes `encode_into` writes.
+    ///
+    /// Implemented without encoding for all types of this crate and `#[derive(Encoder)]`.
+    /// The default implementation encodes into a scratch buffer.
+    fn encoded_len(&self) -> usize {
+        let mut buf = BytesMut::new();
+        self.encode_into(&mut buf);
+        buf.len()
+    }
+
+    /// Encodes into a buffer allocated once, with room for exactly `encoded_len` bytes.
+    fn encode_to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(self.encoded_len());
+        self.encode_into(&mut buf);
+        buf.freeze()
+    }
+}
+
+// Building blocks for application types, e.g. the ones implemented by `#[derive(Encoder)]`.
+// Integers are big-endian, booleans are a single 0 or 1 byte.
+macro_rules! impl_encoder_for_int {
+    ($($t:ty => $put:ident),*) => {
+        $(
+            impl Encoder for $t {
+                fn encode_into(&self, buf: &mut BytesMut) {
+                    buf.$put(*self);
+                }
+
+                fn encoded_len(&self) -> usize {
+                    ::std::mem::size_of::<$t>()
+                }
+            }
+        )*
+    }
+}
+
+impl_encoder_for_int! {
+    u8 => put_u8, u16 => put_u16, u32 => put_u32, u64 => put_u64,
+    i8 => put_i8, i16 => put_i16, i32 => put_i32, i64 => put_i64
+}
+
+impl Encoder for bool {
+    fn encode_into(&self, buf: &mut BytesMut) {
+        (*self as u8).encode_into(buf);
+    }
+
+    fn encoded_len(&self) -> usize {
+        1
+    }
+}
+
+/// `|Element count (4 bytes)|Elements|`
+impl<T: Encoder> Encoder for Vec<T> {
+    fn encode_into(&self, buf: &mut BytesMut) {
+        length_prefix(self.len()).encode_into(buf);
+        for item in self {
+            item.encode_into(buf);
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        4 + self.iter().map(Encoder::encoded_len).sum::<usize>()
+    }
+}
+
+/// `|0|` for `None`, `|1|Value|` for `Some`
+impl<T: Encoder> Encoder for Option<T> {
+    fn encode_into(&self, buf: &mut BytesMut) {
+        match *self {
+            Some(ref value) => {
+                true.encode_into(buf);
+                value.encode_into(buf);
+            },
+            None => false.encode_into(buf)
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        1 + self.as_ref().map_or(0, Encoder::encoded_len)
+    }
+}
+
+impl Encoder for ProtocolVersion {
+    fn encode_into(&self, buf: &mut BytesMut) {
+        buf.extend_from_slice(&consts::FRAME_MAGIC[..]);
+        buf.put_u8(self.0);
+    }
+
+    fn encoded_len(&self) -> usize {
+        consts::FRAME_MAGIC.len() + 1
+    }
+}
+
+// Unsigned LEB128
+fn encode_varint(mut n: u64, buf: &mut BytesMut) {
+    while n >= 0x80 {
+        buf.put_u8(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.put_u8(n as u8);
+}
+
+fn varint_len(n: u64) -> usize {
+    // 7 bits per byte, and at least one byte
+    std::cmp::max(1, (64 - n.leading_zeros() as usize).div_ceil(7))
+}
+
+impl Encoder for Envelope {
+    fn encode_into(&self, buf: &mut BytesMut) {
+        self.id.encode_into(buf);
+        self.sender.encode_into(buf);
+        self.conversation.encode_into(buf);
+        encode_varint(self.timestamp, buf);
+        self.reply_to.encode_into(buf);
+        self.message.encode_into(buf);
+    }
+
+    fn encoded_len(&self) -> usize {
+        3 * 8 + varint_len(self.timestamp) + self.reply_to.encoded_len() + self.message.encoded_len()
+    }
 }
 
 impl Encoder for String {
     fn encode_into(&self, buf: &mut BytesMut) {
-        let len = self.len();
-        if len > consts::TEXT_SLICE_MAX_LENGTH_S {
-            buf.put_u16::<BigEndian>(consts::TEXT_OVERFLOW_FLAG);
-            buf.extend(self[..consts::TEXT_SLICE_MAX_LENGTH_S].bytes());
-            String::from(&self[consts::TEXT_SLICE_MAX_LENGTH_S..]).encode_into(buf);
-        } else {
-            buf.put_u16::<BigEndian>(self.len() as u16);
-            buf.extend(self.as_bytes());
+        // Slices are cut at byte boundaries, which may fall inside a multi-byte character.
+        // The decoder joins all slices before validating the text as UTF-8.
+        encode_sliced(self.as_bytes(), buf);
+    }
+
+    fn encoded_len(&self) -> usize {
+        sliced_len(self.len())
+    }
+}
+
+// Writes `bytes` in slices of at most `consts::TEXT_SLICE_MAX_LENGTH` bytes, like texts.
+fn encode_sliced(mut bytes: &[u8], buf: &mut BytesMut) {
+    while bytes.len() > consts::TEXT_SLICE_MAX_LENGTH_S {
+        buf.put_u16(consts::TEXT_OVERFLOW_FLAG);
+        buf.extend_from_slice(&bytes[..consts::TEXT_SLICE_MAX_LENGTH_S]);
+        bytes = &bytes[consts::TEXT_SLICE_MAX_LENGTH_S..];
+    }
+    buf.put_u16(bytes.len() as u16);
+    buf.extend_from_slice(bytes);
+}
+
+// Number of slices `len` items are written in, when each slice holds at most `max` of them.
+fn slice_count(len: usize, max: usize) -> usize {
+    // Even nothing takes a slice
+    std::cmp::max(1, len.div_ceil(max))
+}
+
+// Length of `len` bytes written by `encode_sliced`
+fn sliced_len(len: usize) -> usize {
+    2 * slice_count(len, consts::TEXT_SLICE_MAX_LENGTH_S) + len
+}
+
+impl Encoder for Style {
+    fn encode_into(&self, buf: &mut BytesMut) {
+        match *self {
+            Style::Bold => buf.put_u8(consts::TEXT_STYLE_CODE_BOLD),
+            Style::Italic => buf.put_u8(consts::TEXT_STYLE_CODE_ITALIC),
+            Style::Code => buf.put_u8(consts::TEXT_STYLE_CODE_CODE),
+            Style::Strikethrough => buf.put_u8(consts::TEXT_STYLE_CODE_STRIKETHROUGH),
+            Style::Link(ref url) => {
+                buf.put_u8(consts::TEXT_STYLE_CODE_LINK);
+                url.encode_into(buf);
+            },
+            Style::Mention(user) => {
+                buf.put_u8(consts::TEXT_STYLE_CODE_MENTION);
+                user.encode_into(buf);
+            }
         }
     }
+
+    fn encoded_len(&self) -> usize {
+        1 + match *self {
+            Style::Bold | Style::Italic | Style::Code | Style::Strikethrough => 0,
+            Style::Link(ref url) => url.encoded_len(),
+            Style::Mention(user) => user.encoded_len()
+        }
+    }
+}
+
+impl Encoder for Span {
+    fn encode_into(&self, buf: &mut BytesMut) {
+        self.start.encode_into(buf);
+        self.end.encode_into(buf);
+        self.style.encode_into(buf);
+    }
+
+    fn encoded_len(&self) -> usize {
+        4 + 4 + self.style.encoded_len()
+    }
+}
+
+impl Encoder for Attachment {
+    fn encode_into(&self, buf: &mut BytesMut) {
+        self.filename.encode_into(buf);
+        self.mime_type.encode_into(buf);
+        self.size.encode_into(buf);
+        buf.extend_from_slice(&self.sha256);
+        self.content.encode_into(buf);
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.filename.encoded_len() + self.mime_type.encoded_len() + 8 + consts::SHA256_LENGTH +
+            self.content.encoded_len()
+    }
+}
+
+impl Encoder for Content {
+    fn encode_into(&self, buf: &mut BytesMut) {
+        match *self {
+            Content::Inline(ref data) => {
+                consts::ATTACHMENT_CONTENT_INLINE.encode_into(buf);
+                encode_sliced(data, buf);
+            },
+            Content::Chunked { transfer, count } => {
+                consts::ATTACHMENT_CONTENT_CHUNKED.encode_into(buf);
+                transfer.encode_into(buf);
+                count.encode_into(buf);
+            }
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        1 + match *self {
+            Content::Inline(ref data) => sliced_len(data.len()),
+            Content::Chunked { .. } => 8 + 4
+        }
+    }
+}
+
+impl Encoder for Chunk {
+    fn encode_into(&self, buf: &mut BytesMut) {
+        self.transfer.encode_into(buf);
+        self.index.encode_into(buf);
+        encode_sliced(&self.data, buf);
+    }
+
+    fn encoded_len(&self) -> usize {
+        8 + 4 + sliced_len(self.data.len())
+    }
+}
+
+impl Encoder for Location {
+    fn encode_into(&self, buf: &mut BytesMut) {
+        self.latitude.encode_into(buf);
+        self.longitude.encode_into(buf);
+        self.accuracy.encode_into(buf);
+        self.name.encode_into(buf);
+        self.live_until.encode_into(buf);
+    }
+
+    fn encoded_len(&self) -> usize {
+        4 + 4 + self.accuracy.encoded_len() + self.name.encoded_len() + self.live_until.encoded_len()
+    }
 }
 
 impl Encoder for Emo {
@@ -33,52 +282,202 @@ impl Encoder for Emo {
             Emo::Cry => {
                 buf.put_u8(consts::MESSAGE_EMO_CODE_CRY);
             },
-            Emo::Custom(_) => {
-                panic!("Not implemented yet");
+            Emo::Custom(ref id, ref image) => {
+                buf.put_u8(consts::MESSAGE_EMO_CODE_CUSTOM);
+                id.encode_into(buf);
+                match *image {
+                    Some((format, ref data)) => {
+                        buf.put_u8(consts::MESSAGE_TYPE_CODE_IMAGE);
+                        encode_image(format, data, buf);
+                    },
+                    None => {
+                        buf.put_u8(consts::MESSAGE_TYPE_CODE_NOP);
+                    }
+                }
+            }
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        1 + match *self {
+            Emo::Nop | Emo::Laugh | Emo::Cry => 0,
+            Emo::Custom(ref id, ref image) => {
+                id.encoded_len() + 1 + image.as_ref().map_or(0, |(_, data)| image_len(data))
             }
         }
     }
 }
 
+// Checks that `len` fits in a 4-byte length prefix. Panics otherwise, since writing the
+// truncated length would corrupt the rest of the stream.
+// Fails unless `code` may be used by application-defined messages, which would otherwise be
+// decoded as whatever built-in message has the code.
+fn extension_code(code: u8) -> u8 {
+    assert!(consts::is_extension_type_code(code), "type code {} is not an extension code", code);
+    code
+}
+
+fn length_prefix(len: usize) -> u32 {
+    assert!(len <= u32::MAX as usize, "length {} does not fit in a 4-byte length prefix", len);
+    len as u32
+}
+
+fn encode_image(format: u8, data: &[u8], buf: &mut BytesMut) {
+    buf.put_u8(format);
+    buf.put_u32(length_prefix(data.len()));
+    buf.extend_from_slice(data);
+}
+
+// Writes `|Length (4 bytes)|Bytes|`, the encoding of a `Vec<u8>`, in one copy.
+fn encode_byte_vec(data: &[u8], buf: &mut BytesMut) {
+    buf.put_u32(length_prefix(data.len()));
+    buf.put_slice(data);
+}
+
+fn byte_vec_len(data: &[u8]) -> usize {
+    4 + data.len()
+}
+
+// Length of an image written by `encode_image`
+fn image_len(data: &[u8]) -> usize {
+    1 + 4 + data.len()
+}
+
+// Writes `|Payload length|Payload|` with the payload written by `encode_payload`.
+fn encode_envelope<F: FnOnce(&mut BytesMut)>(buf: &mut BytesMut, encode_payload: F) {
+    // The payload length is only known once the payload has been written
+    let start = buf.len();
+    buf.put_u32(0);
+    encode_payload(buf);
+    let len = length_prefix(buf.len() - start - consts::ENVELOPE_HEADER_LENGTH);
+    BigEndian::write_u32(&mut buf[start..start + consts::ENVELOPE_HEADER_LENGTH], len);
+}
+
+fn encode_control_payload(ctrl: &Control, buf: &mut BytesMut) {
+    match *ctrl {
+        Control::Ping | Control::Pong | Control::TypingStart | Control::TypingStop => {},
+        Control::Ack(id) | Control::ReadReceipt(id) => id.encode_into(buf),
+        Control::Close(reason) => reason.encode_into(buf),
+        Control::Error { code, ref description } => {
+            code.encode_into(buf);
+            description.encode_into(buf);
+        }
+    }
+}
+
+fn control_payload_len(ctrl: &Control) -> usize {
+    match *ctrl {
+        Control::Ping | Control::Pong | Control::TypingStart | Control::TypingStop => 0,
+        Control::Ack(id) | Control::ReadReceipt(id) => id.encoded_len(),
+        Control::Close(reason) => reason.encoded_len(),
+        Control::Error { code, ref description } => code.encoded_len() + description.encoded_len()
+    }
+}
+
 impl Encoder for Message {
     fn encode_into(&self, buf: &mut BytesMut) {
         // Type code
         buf.put_u8(match *self {
             Message::Nop => consts::MESSAGE_TYPE_CODE_NOP,
+            Message::Control(ref ctrl) => ctrl.code(),
             Message::Text(_) => consts::MESSAGE_TYPE_CODE_TEXT,
+            Message::RichText(..) => consts::MESSAGE_TYPE_CODE_RICH_TEXT,
             Message::Emo(_) => consts::MESSAGE_TYPE_CODE_EMO,
+            Message::Attachment(_) => consts::MESSAGE_TYPE_CODE_ATTACHMENT,
+            Message::Chunk(_) => consts::MESSAGE_TYPE_CODE_CHUNK,
+            Message::Location(_) => consts::MESSAGE_TYPE_CODE_LOCATION,
             Message::Image(..) => consts::MESSAGE_TYPE_CODE_IMAGE,
+            Message::Voice { .. } => consts::MESSAGE_TYPE_CODE_VOICE,
             Message::Compound(_) => consts::MESSAGE_TYPE_CODE_COMPOUND,
+            Message::Binary(_) => consts::MESSAGE_TYPE_CODE_BINARY,
+            Message::Extension { code, .. } => extension_code(code),
+            Message::Object(ref obj) => extension_code(obj.code()),
+            Message::Unknown(code, _) => {
+                // Would be decoded as the built-in message, or not at all if not enveloped
+                assert!(!consts::is_builtin_type_code(code),
+                        "type code {} is taken by a built-in message", code);
+                code
+            },
         });
         match *self {
             Message::Text(ref t) => t.encode_into(buf),
+            Message::RichText(ref t, ref spans) => encode_envelope(buf, |buf| {
+                t.encode_into(buf);
+                spans.encode_into(buf);
+            }),
             Message::Emo(ref e) => e.encode_into(buf),
-            Message::Image(..) => {
-                panic!("Not implemented yet");
+            Message::Attachment(ref a) => encode_envelope(buf, |buf| a.encode_into(buf)),
+            Message::Chunk(ref c) => encode_envelope(buf, |buf| c.encode_into(buf)),
+            Message::Location(ref l) => encode_envelope(buf, |buf| l.encode_into(buf)),
+            Message::Image(format, ref data) => encode_image(format, data, buf),
+            Message::Voice { codec, duration, ref waveform, ref data } => {
+                encode_envelope(buf, |buf| {
+                    codec.encode_into(buf);
+                    duration.encode_into(buf);
+                    match *waveform {
+                        Some(ref waveform) => {
+                            true.encode_into(buf);
+                            encode_byte_vec(waveform, buf);
+                        },
+                        None => false.encode_into(buf)
+                    }
+                    encode_byte_vec(data, buf);
+                })
             },
             Message::Compound(ref msgs) => {
-                let len = msgs.len();
-                if len > consts::COMPOUND_SLICE_MAX_LENGTH_S {
-                    let mut start = 0;
-                    let start_finish_point = len - consts::COMPOUND_SLICE_MAX_LENGTH_S;
-                    while start < start_finish_point {
-                        buf.put_u8(consts::COMPOUND_OVERFLOW_FLAG);
-                        for ref msg in &msgs[start..start+consts::COMPOUND_SLICE_MAX_LENGTH_S] {
-                            msg.encode_into(buf);
-                        }
-                    }
-                    buf.put_u8((len - start) as u8);
-                    for msg in &msgs[start..] {
-                        msg.encode_into(buf);
-                    }
-                } else {
-                    buf.put_u8(len as u8);
-                    for msg in msgs {
+                let mut start = 0;
+                while msgs.len() - start > consts::COMPOUND_SLICE_MAX_LENGTH_S {
+                    buf.put_u8(consts::COMPOUND_OVERFLOW_FLAG);
+                    for msg in &msgs[start..start+consts::COMPOUND_SLICE_MAX_LENGTH_S] {
                         msg.encode_into(buf);
                     }
+                    start += consts::COMPOUND_SLICE_MAX_LENGTH_S;
                 }
+                buf.put_u8((msgs.len() - start) as u8);
+                for msg in &msgs[start..] {
+                    msg.encode_into(buf);
+                }
+            },
+            Message::Binary(ref payload) |
+            Message::Extension { ref payload, .. } |
+            Message::Unknown(_, ref payload) => {
+                buf.put_u32(length_prefix(payload.len()));
+                buf.extend_from_slice(payload);
             },
-            _ => {}
+            Message::Control(ref ctrl) => encode_envelope(buf, |buf| encode_control_payload(ctrl, buf)),
+            Message::Object(ref obj) => encode_envelope(buf, |buf| obj.encode_payload(buf)),
+            Message::Nop => {}
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        let header = consts::ENVELOPE_HEADER_LENGTH;
+        // Type code
+        1 + match *self {
+            Message::Text(ref t) => t.encoded_len(),
+            Message::RichText(ref t, ref spans) => header + t.encoded_len() + spans.encoded_len(),
+            Message::Emo(ref e) => e.encoded_len(),
+            Message::Attachment(ref a) => header + a.encoded_len(),
+            Message::Chunk(ref c) => header + c.encoded_len(),
+            Message::Location(ref l) => header + l.encoded_len(),
+            Message::Image(_, ref data) => image_len(data),
+            Message::Voice { ref waveform, ref data, .. } => {
+                header + 1 + 4 + 1 + waveform.as_ref().map_or(0, |w| byte_vec_len(w)) +
+                    byte_vec_len(data)
+            },
+            Message::Compound(ref msgs) => {
+                // One length byte per slice
+                slice_count(msgs.len(), consts::COMPOUND_SLICE_MAX_LENGTH_S) +
+                    msgs.iter().map(Encoder::encoded_len).sum::<usize>()
+            },
+            Message::Binary(ref payload) |
+            Message::Extension { ref payload, .. } |
+            Message::Unknown(_, ref payload) => {
+                header + payload.len()
+            },
+            Message::Control(ref ctrl) => header + control_payload_len(ctrl),
+            Message::Object(ref obj) => header + obj.payload_len(),
+            Message::Nop => 0
         }
     }
 }
@@ -86,7 +485,10 @@ impl Encoder for Message {
 #[cfg(test)]
 mod tests {
     use bytes::BytesMut;
-    use super::{Message, Emo, Encoder};
+    use super::consts;
+    use super::{Message, Control, Emo, Envelope, ProtocolVersion, Span, Style, Attachment,
+                Content, Chunk, Location, Encoder};
+    use proptest::prelude::*;
 
     #[test]
     fn encode_text() {
@@ -101,6 +503,119 @@ mod tests {
         }
     }
 
+    #[test]
+    fn encode_control() {
+        let cases: Vec<(Control, &[u8])> = vec![
+            // 1 byte type (Ping: 1)
+            // 4 byte payload length 00000000
+            (Control::Ping, b"\x01\x00\x00\x00\x00"),
+            (Control::Pong, b"\x02\x00\x00\x00\x00"),
+            // 8 byte message id
+            (Control::Ack(0x0102), b"\x03\x00\x00\x00\x08\x00\x00\x00\x00\x00\x00\x01\x02"),
+            (Control::TypingStart, b"\x04\x00\x00\x00\x00"),
+            (Control::TypingStop, b"\x05\x00\x00\x00\x00"),
+            (Control::ReadReceipt(7), b"\x06\x00\x00\x00\x08\x00\x00\x00\x00\x00\x00\x00\x07"),
+            // 2 byte reason code
+            (Control::Close(consts::CLOSE_REASON_GOING_AWAY), b"\x07\x00\x00\x00\x02\x00\x01"),
+            // 2 byte error code
+            // Text description
+            (
+                Control::Error { code: 500, description: String::from("oops") },
+                b"\x08\x00\x00\x00\x08\x01\xf4\x00\x04oops"
+            ),
+        ];
+        for (ctrl, expected) in cases {
+            let mut buf = BytesMut::new();
+            Message::Control(ctrl).encode_into(&mut buf);
+            assert_eq!(&buf[..], expected);
+        }
+    }
+
+    #[test]
+    fn encode_rich_text() {
+        let msg = Message::RichText(String::from("Hi @bob"), vec![
+            Span { start: 0, end: 2, style: Style::Bold },
+            Span { start: 3, end: 7, style: Style::Mention(9) },
+        ]);
+        let mut buf = BytesMut::new();
+        msg.encode_into(&mut buf);
+        // 1 byte type (Rich text: 129)
+        // 4 byte payload length
+        // Text
+        // 4 byte span count
+        // 4 byte start, 4 byte end, 1 byte style code, style-specific encoding
+        assert_eq!(&buf[..], b"\
+        \x81\x00\x00\x00\x27\
+        \x00\x07Hi @bob\
+        \x00\x00\x00\x02\
+        \x00\x00\x00\x00\x00\x00\x00\x02\x00\
+        \x00\x00\x00\x03\x00\x00\x00\x07\x05\x00\x00\x00\x00\x00\x00\x00\x09");
+    }
+
+    #[test]
+    fn encode_attachment() {
+        let msg = Message::Attachment(Attachment {
+            filename: String::from("a.txt"),
+            mime_type: String::from("text/plain"),
+            size: 2,
+            sha256: [0xaa; 32],
+            content: Content::Inline(b"hi".to_vec()),
+        });
+        let mut buf = BytesMut::new();
+        msg.encode_into(&mut buf);
+        // 1 byte type (Attachment: 131)
+        // 4 byte payload length
+        // Text file name, Text MIME type
+        // 8 byte size
+        // 32 byte SHA-256
+        // 1 byte content kind (Inline: 0)
+        // Sliced content
+        let mut expected = b"\x83\x00\x00\x00\x40\x00\x05a.txt\x00\x0atext/plain\
+        \x00\x00\x00\x00\x00\x00\x00\x02".to_vec();
+        expected.extend_from_slice(&[0xaa; 32]);
+        expected.extend_from_slice(b"\x00\x00\x02hi");
+        assert_eq!(&buf[..], &expected[..]);
+
+        let msg = Message::Chunk(Chunk { transfer: 5, index: 1, data: b"abc".to_vec() });
+        let mut buf = BytesMut::new();
+        msg.encode_into(&mut buf);
+        // 1 byte type (Chunk: 132)
+        // 4 byte payload length
+        // 8 byte transfer id
+        // 4 byte chunk index
+        // Sliced chunk data
+        assert_eq!(&buf[..], b"\
+        \x84\x00\x00\x00\x11\
+        \x00\x00\x00\x00\x00\x00\x00\x05\
+        \x00\x00\x00\x01\
+        \x00\x03abc");
+    }
+
+    #[test]
+    fn encode_location() {
+        let msg = Message::Location(Location {
+            latitude: 35_681_236,
+            longitude: -1,
+            accuracy: Some(20),
+            name: None,
+            live_until: None,
+        });
+        let mut buf = BytesMut::new();
+        msg.encode_into(&mut buf);
+        // 1 byte type (Location: 133)
+        // 4 byte payload length
+        // 4 byte latitude, 4 byte longitude
+        // 1 byte accuracy flag, 4 byte accuracy
+        // 1 byte place name flag
+        // 1 byte live until flag
+        assert_eq!(&buf[..], b"\
+        \x85\x00\x00\x00\x0f\
+        \x02\x20\x73\xd4\xff\xff\xff\xff\
+        \x01\x00\x00\x00\x14\
+        \x00\
+        \x00");
+    }
+
     #[test]
     fn encode_emo() {
         {
@@ -121,13 +636,67 @@ mod tests {
             msg.encode_into(&mut buf);
             assert_eq!(&buf[..], b"\x82\x02");
         }
-        {  // TODO: Custom emo
+        {
+            let msg = Message::Emo(Emo::Custom(String::from("pack/wave"), None));
+            let mut buf = BytesMut::new();
+            msg.encode_into(&mut buf);
+            assert_eq!(&buf[..], b"\x82\xf0\x00\x09pack/wave\x00");
+        }
+        {
+            let msg = Message::Emo(Emo::Custom(
+                String::from("pack/wave"),
+                Some((consts::MESSAGE_IMAGE_FORMAT_GIF, b"GIF8".to_vec()))
+            ));
+            let mut buf = BytesMut::new();
+            msg.encode_into(&mut buf);
+            assert_eq!(&buf[..], b"\x82\xf0\x00\x09pack/wave\x8c\x02\x00\x00\x00\x04GIF8");
         }
     }
 
     #[test]
     fn encode_img() {
-        // TODO
+        let msg = Message::Image(consts::MESSAGE_IMAGE_FORMAT_PNG, b"\x89PNG".to_vec());
+        let mut buf = BytesMut::new();
+        msg.encode_into(&mut buf);
+        // 1 byte type (Image: 140)
+        // 1 byte format (PNG: 0)
+        // 4 byte data length 00000004
+        // 4 byte data
+        assert_eq!(&buf[..], b"\x8c\x00\x00\x00\x00\x04\x89PNG");
+    }
+
+    #[test]
+    fn encode_voice() {
+        let msg = Message::Voice {
+            codec: consts::MESSAGE_VOICE_CODEC_OPUS,
+            duration: 1500,
+            waveform: Some(vec![0x10, 0x7f]),
+            data: b"Opus".to_vec(),
+        };
+        let mut buf = BytesMut::new();
+        msg.encode_into(&mut buf);
+        // 1 byte type (Voice: 141)
+        // 4 byte payload length
+        // 1 byte codec (Opus: 0)
+        // 4 byte duration 000005dc
+        // 1 byte waveform flag, 4 byte sample count, samples
+        // 4 byte data length, data
+        assert_eq!(&buf[..], b"\
+        \x8d\x00\x00\x00\x14\
+        \x00\
+        \x00\x00\x05\xdc\
+        \x01\x00\x00\x00\x02\x10\x7f\
+        \x00\x00\x00\x04Opus");
+    }
+
+    #[test]
+    fn encode_binary() {
+        let msg = Message::Binary(vec![0x00, 0xff, 0x10]);
+        let mut buf = BytesMut::new();
+        msg.encode_into(&mut buf);
+        // 1 byte type (Binary: 251)
+        // 4 byte payload length, bytes
+        assert_eq!(&buf[..], b"\xfb\x00\x00\x00\x03\x00\xff\x10");
     }
 
     #[test]
@@ -147,4 +716,139 @@ mod tests {
         \x82\x01\
         \x80\x00\x06world!");
     }
+
+    #[test]
+    fn encode_compound_overflow() {
+        let msg = Message::Compound((0..255).map(|_| Message::Nop).collect());
+        let mut buf = BytesMut::new();
+        msg.encode_into(&mut buf);
+        // 1 byte type (Compound: 250)
+        // 1 byte overflow flag, followed by the first 254 messages
+        // 1 byte length of the remaining 1 message
+        let mut expected = vec![0xfa, 0xff];
+        expected.extend(vec![0x00; 254]);
+        expected.extend(vec![0x01, 0x00]);
+        assert_eq!(&buf[..], &expected[..]);
+    }
+
+    #[test]
+    fn encode_frame_header() {
+        let mut buf = BytesMut::new();
+        ProtocolVersion::CURRENT.encode_into(&mut buf);
+        Message::Text(String::from("Hi")).encode_into(&mut buf);
+        // 4 byte magic "ITRE"
+        // 1 byte version (0.1: 1)
+        assert_eq!(&buf[..], b"ITRE\x01\x80\x00\x02Hi");
+    }
+
+    #[test]
+    fn encode_envelope() {
+        let envelope = Envelope {
+            id: 1,
+            sender: 2,
+            conversation: 3,
+            timestamp: 1_500_000_000_000,
+            reply_to: Some(0xff),
+            message: Message::Nop,
+        };
+        let mut buf = BytesMut::new();
+        envelope.encode_into(&mut buf);
+        // 8 byte message id
+        // 8 byte sender id
+        // 8 byte conversation id
+        // 6 byte timestamp varint (0x15d3ef79800)
+        // 9 byte reply-to id
+        // 1 byte message (Nop)
+        assert_eq!(&buf[..], b"\
+        \x00\x00\x00\x00\x00\x00\x00\x01\
+        \x00\x00\x00\x00\x00\x00\x00\x02\
+        \x00\x00\x00\x00\x00\x00\x00\x03\
+        \x80\xb0\xde\xf7\xd3\x2b\
+        \x01\x00\x00\x00\x00\x00\x00\x00\xff\
+        \x00");
+    }
+
+    #[test]
+    fn encode_primitives() {
+        let mut buf = BytesMut::new();
+        0x12u8.encode_into(&mut buf);
+        0x1234u16.encode_into(&mut buf);
+        (-2i32).encode_into(&mut buf);
+        true.encode_into(&mut buf);
+        vec![1u16, 2u16].encode_into(&mut buf);
+        Some(7i8).encode_into(&mut buf);
+        None::<u64>.encode_into(&mut buf);
+        assert_eq!(&buf[..], b"\
+        \x12\
+        \x12\x34\
+        \xff\xff\xff\xfe\
+        \x01\
+        \x00\x00\x00\x02\x00\x01\x00\x02\
+        \x01\x07\
+        \x00");
+    }
+
+    #[test]
+    #[should_panic(expected = "does not fit in a 4-byte length prefix")]
+    fn encode_length_overflow() {
+        super::length_prefix(u32::MAX as usize + 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "type code 128 is not an extension code")]
+    fn encode_extension_builtin_code() {
+        let msg = Message::Extension { code: consts::MESSAGE_TYPE_CODE_TEXT, payload: vec![0, 0, 0] };
+        msg.encode_into(&mut BytesMut::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "type code 1 is taken by a built-in message")]
+    fn encode_unknown_builtin_code() {
+        // Would decode as `Control::Ping`
+        Message::Unknown(consts::MESSAGE_TYPE_CODE_PING, vec![]).encode_into(&mut BytesMut::new());
+    }
+
+    // Encodes `value` into a buffer sized by `encoded_len`, which must be exact.
+    fn check_encoded_len<T: Encoder>(value: &T) {
+        let len = value.encoded_len();
+        let mut buf = BytesMut::with_capacity(len);
+        value.encode_into(&mut buf);
+        assert_eq!(buf.len(), len);
+        // Never had to grow
+        assert_eq!(buf.capacity(), len);
+        assert_eq!(value.encode_to_bytes(), buf.freeze());
+    }
+
+    #[test]
+    fn encoded_len() {
+        let max_text = consts::TEXT_SLICE_MAX_LENGTH_S;
+        for &len in &[0, 1, max_text - 1, max_text, max_text + 1, 2 * max_text, 2 * max_text + 1] {
+            check_encoded_len(&Message::Text("x".repeat(len)));
+            check_encoded_len(&Message::Chunk(Chunk { transfer: 1, index: 2, data: vec![0; len] }));
+        }
+        let max_compound = consts::COMPOUND_SLICE_MAX_LENGTH_S;
+        for &len in &[0, 1, max_compound, max_compound + 1, 2 * max_compound, 2 * max_compound + 1] {
+            check_encoded_len(&Message::Compound((0..len).map(|_| Message::Nop).collect()));
+        }
+        for &timestamp in &[0, 0x7f, 0x80, 0x3fff, 0x4000, 1 << 63, u64::MAX] {
+            check_encoded_len(&Envelope {
+                id: 1,
+                sender: 2,
+                conversation: 3,
+                timestamp,
+                reply_to: None,
+                message: Message::Nop,
+            });
+        }
+        check_encoded_len(&ProtocolVersion::CURRENT);
+        check_encoded_len(&vec![Some(1u16), None]);
+    }
+
+    proptest! {
+        #[test]
+        fn encoded_len_arbitrary(msg in any::<Message>(), envelope in any::<Envelope>()) {
+            check_encoded_len(&msg);
+            check_encoded_len(&envelope);
+        }
+    }
 }
@@ -1,8 +1,256 @@
 use std;
+use std::fmt;
 
 #[derive(Debug)]
 pub enum Error {
     InvalidTypeCode(u8),
     InvalidEmoCode(u8),
-    IOError(std::io::Error)
-}
\ No newline at end of file
+    InvalidImageFormat(u8),
+    InvalidVoiceCodec(u8),
+    InvalidStyleCode(u8),
+    /// A rich text span does not delimit a range of whole characters within its text.
+    /// Carries the span's start and end.
+    InvalidSpan(u32, u32),
+    /// The input ended before the message was complete. Carries the number of
+    /// additional bytes the decoder needed at the point where it stopped.
+    UnexpectedEof(usize),
+    /// A text is not valid UTF-8. Carries the byte offset within the text where the
+    /// invalid sequence starts.
+    InvalidUtf8(usize),
+    /// A frame header does not start with `consts::FRAME_MAGIC`. Carries the bytes found instead.
+    InvalidFrameMagic(Vec<u8>),
+    /// A frame header carries a protocol version outside of `DecodeOptions::versions`.
+    UnsupportedVersion(u8),
+    /// A boolean or `Option` tag byte is neither 0 nor 1.
+    InvalidFlag(u8),
+    /// A location's latitude or longitude is out of range. Carries both, in microdegrees.
+    InvalidCoordinates(i32, i32),
+    /// A varint does not fit in 64 bits.
+    InvalidVarint,
+    /// An element of a `Vec` took up no input, as for a unit struct. Such vectors cannot be
+    /// decoded, since their length would not be bounded by the input.
+    EmptyElement,
+    InvalidContentKind(u8),
+    /// A chunk's index is outside of its attachment's chunk count, or the chunk was
+    /// already received. Carries the chunk index.
+    InvalidChunk(u32),
+    /// The content of a reassembled attachment does not match its declared size.
+    /// Carries the number of bytes received.
+    AttachmentSizeMismatch(u64),
+    /// The content of a reassembled attachment does not match its SHA-256 digest.
+    DigestMismatch,
+    /// The input exceeds one of the `decoder::DecodeLimits`.
+    LimitExceeded(Limit),
+    /// An attachment or chunk exceeds one of the `attachment::ReassemblyLimits`.
+    ReassemblyLimitExceeded(ReassemblyLimit),
+    /// The code cannot be registered: it is outside of the extension range, taken by a
+    /// built-in message or already registered.
+    TypeCodeUnavailable(u8),
+    IOError(std::io::Error),
+    /// Another error, along with where in the input it happened. Returned when decoding
+    /// messages and envelopes.
+    Context(Box<Context>)
+}
+
+/// The `decoder::DecodeLimits` field that was exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Depth,
+    TotalBytes,
+    Children,
+    StringLength,
+}
+
+/// The `attachment::ReassemblyLimits` field that was exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReassemblyLimit {
+    Transfers,
+    Chunks,
+    Bytes,
+}
+
+/// Where in the input decoding failed.
+#[derive(Debug)]
+pub struct Context {
+    /// Nested messages leading to the failing one, outermost first, e.g.
+    /// `["compound", "[3]", "text"]`
+    pub path: Vec<String>,
+    /// Position in the input at which decoding stopped, relative to the start of the
+    /// value being decoded. For an invalid code this is just past the code, not at it.
+    pub offset: usize,
+    /// Never a `Context` itself
+    pub error: Error,
+}
+
+impl Error {
+    /// The error without its context.
+    pub fn kind(&self) -> &Error {
+        match *self {
+            Error::Context(ref ctx) => &ctx.error,
+            ref e => e
+        }
+    }
+
+    /// The error without its context.
+    pub fn into_kind(self) -> Error {
+        match self {
+            Error::Context(ctx) => ctx.error,
+            e => e
+        }
+    }
+
+    pub fn context(&self) -> Option<&Context> {
+        match *self {
+            Error::Context(ref ctx) => Some(ctx),
+            _ => None
+        }
+    }
+
+    // Records that the error happened at `offset`, unless its position is already known.
+    pub(crate) fn at(self, offset: usize) -> Error {
+        match self {
+            Error::Context(ctx) => Error::Context(ctx),
+            error => Error::Context(Box::new(Context { path: Vec::new(), offset, error }))
+        }
+    }
+
+    // Records that the error happened inside `segment`, which starts at `offset`.
+    pub(crate) fn within(self, segment: String, offset: usize) -> Error {
+        let mut ctx = match self.at(0) {
+            Error::Context(ctx) => ctx,
+            _ => unreachable!()
+        };
+        ctx.path.insert(0, segment);
+        ctx.offset += offset;
+        Error::Context(ctx)
+    }
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, segment) in self.path.iter().enumerate() {
+            if i > 0 && !segment.starts_with('[') {
+                f.write_str(".")?;
+            }
+            f.write_str(segment)?;
+        }
+        if !self.path.is_empty() {
+            f.write_str(" ")?;
+        }
+        write!(f, "at offset {}: {}", self.offset, self.error)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidTypeCode(code) => write!(f, "invalid type code {}", code),
+            Error::InvalidEmoCode(code) => write!(f, "invalid emo code {}", code),
+            Error::InvalidImageFormat(format) => write!(f, "invalid image format {}", format),
+            Error::InvalidVoiceCodec(codec) => write!(f, "invalid voice codec {}", codec),
+            Error::InvalidStyleCode(code) => write!(f, "invalid style code {}", code),
+            Error::InvalidSpan(start, end) => write!(f, "invalid span {}..{}", start, end),
+            Error::UnexpectedEof(needed) => {
+                write!(f, "unexpected end of input, {} more bytes needed", needed)
+            },
+            Error::InvalidUtf8(valid_up_to) => {
+                write!(f, "invalid utf-8 after {} valid bytes", valid_up_to)
+            },
+            Error::InvalidFrameMagic(ref magic) => write!(f, "invalid frame magic {:?}", magic),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {}", version)
+            },
+            Error::InvalidFlag(flag) => write!(f, "invalid flag {}", flag),
+            Error::InvalidCoordinates(latitude, longitude) => {
+                write!(f, "invalid coordinates ({}, {})", latitude, longitude)
+            },
+            Error::InvalidVarint => f.write_str("varint overflows 64 bits"),
+            Error::EmptyElement => f.write_str("vector element of zero bytes"),
+            Error::InvalidContentKind(kind) => write!(f, "invalid attachment content kind {}", kind),
+            Error::InvalidChunk(index) => write!(f, "invalid or duplicate chunk {}", index),
+            Error::AttachmentSizeMismatch(received) => {
+                write!(f, "attachment size mismatch, {} bytes received", received)
+            },
+            Error::DigestMismatch => f.write_str("attachment SHA-256 digest mismatch"),
+            Error::LimitExceeded(limit) => {
+                let name = match limit {
+                    Limit::Depth => "max_depth",
+                    Limit::TotalBytes => "max_total_bytes",
+                    Limit::Children => "max_children",
+                    Limit::StringLength => "max_string_length",
+                };
+                write!(f, "decode limit {} exceeded", name)
+            },
+            Error::ReassemblyLimitExceeded(limit) => {
+                let name = match limit {
+                    ReassemblyLimit::Transfers => "max_transfers",
+                    ReassemblyLimit::Chunks => "max_chunks",
+                    ReassemblyLimit::Bytes => "max_pending_bytes",
+                };
+                write!(f, "reassembly limit {} exceeded", name)
+            },
+            Error::TypeCodeUnavailable(code) => write!(f, "type code {} is unavailable", code),
+            Error::IOError(ref e) => write!(f, "I/O error: {}", e),
+            Error::Context(ref ctx) => ctx.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self.kind() {
+            Error::IOError(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::IOError(e)
+    }
+}
+
+/// Error returned by the serde `ser` and `de` modules.
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+pub enum SerdeError {
+    /// The value does not fit the ITRE data model, or the message does not fit the value
+    Custom(String),
+    /// The bytes could not be decoded into a message in the first place
+    Decode(Error),
+}
+
+#[cfg(feature = "serde")]
+impl From<Error> for SerdeError {
+    fn from(e: Error) -> SerdeError {
+        SerdeError::Decode(e)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl fmt::Display for SerdeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SerdeError::Custom(ref msg) => f.write_str(msg),
+            SerdeError::Decode(ref e) => write!(f, "decode error: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl std::error::Error for SerdeError {}
+
+#[cfg(feature = "serde")]
+impl serde::ser::Error for SerdeError {
+    fn custom<T: fmt::Display>(msg: T) -> SerdeError {
+        SerdeError::Custom(msg.to_string())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::de::Error for SerdeError {
+    fn custom<T: fmt::Display>(msg: T) -> SerdeError {
+        SerdeError::Custom(msg.to_string())
+    }
+}
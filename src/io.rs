@@ -0,0 +1,250 @@
+use std::io::{self, Read, Write};
+use bytes::BytesMut;
+use super::{Message, ProtocolVersion};
+use super::decoder::{DecodeOptions, Result};
+use super::encoder::Encoder;
+use super::error::Error;
+use super::stream::StreamDecoder;
+
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+/// Writes encoded messages to a synchronous stream.
+pub struct MessageWriter<W: Write> {
+    inner: W,
+    buf: BytesMut,
+}
+
+impl<W: Write> MessageWriter<W> {
+    pub fn new(inner: W) -> MessageWriter<W> {
+        MessageWriter {
+            inner,
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// Writes the frame header announcing `version`, which must precede every message when
+    /// the stream is read with `DecodeOptions::frame_header` set.
+    pub fn write_header(&mut self, version: ProtocolVersion) -> Result<()> {
+        self.write_encoded(&version)
+    }
+
+    /// Encodes `msg` and writes all of it to the underlying stream.
+    pub fn write(&mut self, msg: &Message) -> Result<()> {
+        self.write_encoded(msg)
+    }
+
+    fn write_encoded<T: Encoder>(&mut self, value: &T) -> Result<()> {
+        self.buf.clear();
+        value.encode_into(&mut self.buf);
+        self.inner.write_all(&self.buf)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.inner.flush()?;
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+/// Reads and decodes messages from a synchronous stream.
+///
+/// Bytes read past the end of a message are kept for the next call to `read`.
+pub struct MessageReader<R: Read> {
+    inner: R,
+    buf: BytesMut,
+    decoder: StreamDecoder,
+}
+
+impl<R: Read> MessageReader<R> {
+    pub fn new(inner: R) -> MessageReader<R> {
+        MessageReader::with_options(inner, DecodeOptions::default())
+    }
+
+    pub fn with_options(inner: R, opts: DecodeOptions) -> MessageReader<R> {
+        MessageReader {
+            inner,
+            buf: BytesMut::new(),
+            decoder: StreamDecoder::with_options(opts),
+        }
+    }
+
+    /// Reads the next message.
+    ///
+    /// Returns `Ok(None)` if the stream ends cleanly between two messages. A stream that ends
+    /// in the middle of a message yields an `Error::IOError` of kind `UnexpectedEof`.
+    /// Decoding errors are wrapped in an `Error::Context`, see `stream::StreamDecoder`.
+    pub fn read(&mut self) -> Result<Option<Message>> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        loop {
+            if let Some(msg) = self.decoder.decode(&mut self.buf)? {
+                return Ok(Some(msg));
+            }
+            let n = match self.inner.read(&mut chunk) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::IOError(e))
+            };
+            if n == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(Error::IOError(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "stream ended in the middle of a message"
+                )));
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Iterator for MessageReader<R> {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Result<Message>> {
+        match self.read() {
+            Ok(Some(msg)) => Some(Ok(msg)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Read, Write};
+    use super::super::consts;
+    use super::super::{Message, Emo, ProtocolVersion};
+    use super::super::decoder::DecodeOptions;
+    use super::super::error::Error;
+    use super::{MessageReader, MessageWriter};
+
+    // Hands out at most 3 bytes per read, interrupting every other call
+    struct Trickle<R: Read> {
+        inner: R,
+        interrupt: bool,
+    }
+
+    impl<R: Read> Read for Trickle<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+            }
+            let len = buf.len().min(3);
+            self.inner.read(&mut buf[..len])
+        }
+    }
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn sample_messages() -> Vec<Message> {
+        vec![
+            Message::Text(String::from("ITRE解码测试")),
+            Message::Compound(vec![Message::Emo(Emo::Cry), Message::Nop]),
+            Message::Image(consts::MESSAGE_IMAGE_FORMAT_JPEG, vec![0xd8; 20000]),
+        ]
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut writer = MessageWriter::new(Vec::new());
+        for msg in &sample_messages() {
+            writer.write(msg).unwrap();
+        }
+        writer.flush().unwrap();
+        let encoded = writer.into_inner();
+
+        let reader = MessageReader::new(Cursor::new(encoded.clone()));
+        let decoded: Vec<Message> = reader.map(|msg| msg.unwrap()).collect();
+        assert_eq!(decoded, sample_messages());
+
+        let reader = MessageReader::new(Trickle { inner: Cursor::new(encoded), interrupt: false });
+        let decoded: Vec<Message> = reader.map(|msg| msg.unwrap()).collect();
+        assert_eq!(decoded, sample_messages());
+    }
+
+    #[test]
+    fn read_frame_header() {
+        let mut writer = MessageWriter::new(Vec::new());
+        writer.write_header(ProtocolVersion::CURRENT).unwrap();
+        for msg in &sample_messages() {
+            writer.write(msg).unwrap();
+        }
+        let encoded = writer.into_inner();
+
+        let opts = DecodeOptions { frame_header: true, ..DecodeOptions::default() };
+        let inner = Trickle { inner: Cursor::new(encoded), interrupt: false };
+        let decoded: Vec<Message> = MessageReader::with_options(inner, opts)
+            .map(|msg| msg.unwrap())
+            .collect();
+        assert_eq!(decoded, sample_messages());
+    }
+
+    #[test]
+    fn read_truncated() {
+        let mut writer = MessageWriter::new(Vec::new());
+        writer.write(&Message::Text(String::from("Hello"))).unwrap();
+        let mut encoded = writer.into_inner();
+        encoded.pop();
+        let mut reader = MessageReader::new(Cursor::new(encoded));
+        match reader.read() {
+            Err(Error::IOError(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn write_failure() {
+        let mut writer = MessageWriter::new(Broken);
+        match writer.write(&Message::Nop) {
+            Err(Error::IOError(ref e)) if e.kind() == io::ErrorKind::BrokenPipe => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn error_trait() {
+        fn write_nop() -> Result<(), Box<dyn std::error::Error>> {
+            MessageWriter::new(Broken).write(&Message::Nop)?;
+            Ok(())
+        }
+        let err = write_nop().unwrap_err();
+        assert_eq!(err.to_string(), "I/O error: broken");
+        assert_eq!(err.source().unwrap().to_string(), "broken");
+    }
+}
@@ -7,21 +7,93 @@
 //!     | 1 byte  | ...                  |
 //! </pre>
 //!
+//! A stream may optionally start with a frame header that identifies the protocol version
+//! its messages were encoded with:
+//! <pre>
+//!     |Magic ("ITRE")|Version|
+//!     | 4 bytes      | 1 byte|
+//! </pre>
+//! Version 0.1 is encoded as 1(0x1). Decoders reject versions outside the range they accept
+//! (see `decoder::DecodeOptions::versions`). Stream decoders only expect the header when
+//! told to (see `decoder::DecodeOptions::frame_header`).
+//!
+//! A message may be wrapped in an `Envelope` carrying its metadata:
+//! <pre>
+//!     |Message id|Sender id|Conversation id|Timestamp|Reply-to id|Message|
+//!     | 8 bytes  | 8 bytes | 8 bytes       | varint  | ...       | ...   |
+//! </pre>
+//! The timestamp is in milliseconds since the Unix epoch, encoded as an unsigned LEB128
+//! varint: 7 bits per byte, least significant group first, with the high bit set on every
+//! byte but the last. The reply-to id is a single 0(0x0) if absent, otherwise 1(0x1)
+//! followed by the 8-byte id.
+//!
 //! ITRE format categorizes messages into two types: control messages and ordinary messages.
 //! The type code of control messages ranges from 0(0x0) ~ 127(0x7F)
 //! The type code of ordinary messages ranges from 128(0x80) ~ 255(0xFF)
 //!
 //! Control messages:
 //! Nop (0(0x0)): Do nothing
+//! Ping (1(0x1)): Asks the peer to answer with a Pong
+//! Pong (2(0x2)): Answers a Ping
+//! Ack (3(0x3)): A message has been received
+//! Typing start (4(0x4)) / Typing stop (5(0x5)): The user started or stopped typing
+//! Read receipt (6(0x6)): Messages up to and including the given one have been read
+//! Close (7(0x7)): The peer is closing the connection
+//! Error (8(0x8)): The peer hit an error
 //!
 //! Ordinary messages:
 //! Text (128(0x80)): UTF-8 encoded text string
+//! Rich text (129(0x81)): Text with formatting spans
 //! Emo (130(0x82)): Emoticon
+//! Attachment (131(0x83)): File, inline or announcing a chunked transfer
+//! Chunk (132(0x84)): Piece of the content of a chunked attachment
+//! Location (133(0x85)): Geographic position, possibly shared live
 //! Image (140(0x8C)): Image
+//! Voice (141(0x8D)): Voice note
 //! Compound (250(0xFA)): Array of nested messages
+//! Binary (251(0xFB)): Opaque byte array
+//! Extension (134(0x86) ~ 139(0x8B), 142(0x8E) ~ 249(0xF9)): Application-defined
+//!
+//! Every type code other than Nop, Text, Emo, Image and Compound, including the unassigned
+//! control codes and 252(0xFC) ~ 255(0xFF), is followed by a length-delimited envelope:
+//! <pre>
+//!     |Type code|Payload length|Payload|
+//!     | 1 byte  | 4 bytes      | ...   |
+//! </pre>
+//! The payload length is big-endian. Like every length written in 4 bytes (image data,
+//! element counts), it is limited to 2^32-1; encoding a longer payload panics rather than
+//! corrupting the stream. Message types added later use this envelope, so
+//! decoders that do not know a type code can still skip over it. Such messages decode into
+//! `Message::Unknown` holding the type code and the raw payload, which encodes back into
+//! the exact same bytes.
 //!
 //! Type-specific encoding:
-//! Control messages do not bear extra information and thus require no type-specific encoding.
+//! Nop does not bear extra information and thus requires no type-specific encoding.
+//! The other control messages use the length-delimited envelope. Their payloads are:
+//!   Ping, Pong, Typing start, Typing stop: None
+//!   Ack, Read receipt:
+//!     <pre>
+//!         |Message id|
+//!         | 8 bytes  |
+//!     </pre>
+//!   Close:
+//!     <pre>
+//!         |Reason code|
+//!         | 2 bytes   |
+//!     </pre>
+//!     Reason codes:
+//!       Normal (0(0x0))
+//!       Going away (1(0x1))
+//!       Protocol error (2(0x2))
+//!     Other reason codes are application-defined.
+//!   Error:
+//!     <pre>
+//!         |Error code|Description|
+//!         | 2 bytes  | Text      |
+//!     </pre>
+//!     The description uses the type-specific encoding of Text.
+//! Integers are big-endian. Decoders ignore bytes following the payloads above, so later
+//! versions may append fields to them.
 //! For ordinary messages:
 //!   Text:
 //!     <pre>
@@ -36,19 +108,109 @@
 //!     followed by:
 //!     <pre>
 //!         | the type-specific encoding of the remaining text |
+//!     </pre>
 //!     Note that the above rule is applied recursively.
+//!     Slices are cut at byte positions and may split a multi-byte character, so the
+//!     slices are joined before the text is validated as UTF-8.
+//!     Arbitrary bytes are sliced the same way where noted below ("sliced bytes").
+//!
+//!   Rich text:
+//!     Uses the length-delimited envelope. The payload is:
+//!     <pre>
+//!         |Text|Span count|Spans|
+//!         | ...| 4 bytes  | ... |
+//!     </pre>
+//!     The text uses the type-specific encoding of Text. Each span is:
+//!     <pre>
+//!         |Start  |End    |Style code|Style-specific encoding|
+//!         |4 bytes|4 bytes| 1 byte   | ...                   |
+//!     </pre>
+//!     Start and end are byte offsets into the text, end exclusive. Both must fall on
+//!     character boundaries within the text, otherwise decoding fails. Spans may overlap.
+//!     Style-specific encoding:
+//!       Bold (0(0x0)): None
+//!       Italic (1(0x1)): None
+//!       Code (2(0x2)): None
+//!       Strikethrough (3(0x3)): None
+//!       Link (4(0x4)): Target URL, using the type-specific encoding of Text
+//!       Mention (5(0x5)): 8-byte user id
 //!
 //!   Emo:
 //!     <pre>
 //!         |Emo code|Emo-specific encoding|
-//!         | 1 byte | undefined |
+//!         | 1 byte | ...                 |
 //!     </pre>
 //!     Emo-specific encoding:
-//!       Nop (0(0x0))
-//!       Laugh (1(0x1))
-//!       Cry (2(0x2))
-//!       Custom(10(0xF0)): Reserved for future purpose
-//!   Image: TODO
+//!       Nop (0(0x0)): None
+//!       Laugh (1(0x1)): None
+//!       Cry (2(0x2)): None
+//!       Custom (240(0xF0)):
+//!         <pre>
+//!             |Identifier|Inline image            |
+//!             | Text     | Nop or Image message   |
+//!         </pre>
+//!         The identifier (e.g. "pack/sticker") uses the type-specific encoding of Text.
+//!         It is followed by a complete Nop message (a single 0x0) when the emoticon carries
+//!         no image, or by a complete Image message holding the inline image.
+//!   Attachment:
+//!     Uses the length-delimited envelope. The payload is:
+//!     <pre>
+//!         |File name|MIME type|Size   |SHA-256 |Content kind|Content-specific encoding|
+//!         | Text    | Text    |8 bytes|32 bytes| 1 byte     | ...                     |
+//!     </pre>
+//!     Size is the length of the whole content and SHA-256 its digest.
+//!     Content kinds:
+//!       Inline (0(0x0)): The content as sliced bytes
+//!       Chunked (1(0x1)):
+//!         <pre>
+//!             |Transfer id|Chunk count|
+//!             | 8 bytes   | 4 bytes   |
+//!         </pre>
+//!         The content follows in Chunk messages with the same transfer id.
+//!   Chunk:
+//!     Uses the length-delimited envelope. The payload is:
+//!     <pre>
+//!         |Transfer id|Chunk index|Chunk data  |
+//!         | 8 bytes   | 4 bytes   |sliced bytes|
+//!     </pre>
+//!     Chunk indices start at 0. Chunks may arrive in any order; the content is their data
+//!     concatenated in index order (see `attachment::Reassembler`).
+//!   Location:
+//!     Uses the length-delimited envelope. The payload is:
+//!     <pre>
+//!         |Latitude|Longitude|Accuracy|Place name|Live until|
+//!         |4 bytes |4 bytes  | ...    | ...      | ...      |
+//!     </pre>
+//!     Latitude (-90 ~ 90) and longitude (-180 ~ 180) are signed microdegrees, i.e.
+//!     degrees times 10^6. Decoding fails if they are out of range.
+//!     The remaining fields are optional: a single 0(0x0) if absent, otherwise 1(0x1)
+//!     followed by the value. The accuracy is a 4-byte radius in meters and the place
+//!     name uses the type-specific encoding of Text. Live until is present for live
+//!     locations and holds the 8-byte time the sharing expires at, in milliseconds since
+//!     the Unix epoch.
+//!   Image:
+//!     <pre>
+//!         |Image format|Data length|Image data|
+//!         | 1 byte     | 4 bytes   | ...      |
+//!     </pre>
+//!     Image formats:
+//!       PNG (0(0x0))
+//!       JPEG (1(0x1))
+//!       GIF (2(0x2))
+//!       WebP (3(0x3))
+//!     The data length is big-endian, so a single image may carry up to 2^32-1 bytes.
+//!   Voice:
+//!     Uses the length-delimited envelope. The payload is:
+//!     <pre>
+//!         |Codec |Duration|Waveform|Data length|Audio data|
+//!         |1 byte|4 bytes | ...    | 4 bytes   | ...      |
+//!     </pre>
+//!     Codecs:
+//!       Opus (0(0x0))
+//!       AAC (1(0x1))
+//!     The duration is in milliseconds. The waveform is an optional preview of the
+//!     amplitudes: a single 0(0x0) if absent, otherwise 1(0x1) followed by the 4-byte
+//!     number of samples and one byte per sample.
 //!   Compound:
 //!     <pre>
 //!         |Length of nested messages|Encoding of nested messages|
@@ -64,30 +226,209 @@
 //!         | the type-specific encoding of the remaining messages as a compound message |
 //!     </pre>
 //!     The above rule is also applied recursively.
+//!   Extension:
+//!     Uses the length-delimited envelope.
+//!     Applications register the codes they use in a `registry::Registry`. Registered codes
+//!     decode into `Message::Extension` with the raw payload, or into `Message::Object` for
+//!     codes registered with a type. Unregistered codes decode into `Message::Unknown`.
+//!   Binary:
+//!     Uses the length-delimited envelope. The payload is the byte array.
 
 extern crate bytes;
 extern crate byteorder;
+extern crate sha2;
+#[cfg(feature = "tokio")]
+extern crate tokio_util;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "derive")]
+extern crate itre_derive;
+#[cfg(feature = "proptest")]
+extern crate proptest;
 
-mod consts;
+pub mod consts;
 pub mod encoder;
 pub mod decoder;
+pub mod stream;
+pub mod io;
+pub mod registry;
+pub mod attachment;
+pub mod view;
+#[cfg(feature = "tokio")]
+pub mod codec;
+#[cfg(feature = "serde")]
+pub mod ser;
+#[cfg(feature = "serde")]
+pub mod de;
+#[cfg(any(test, feature = "proptest"))]
+pub mod arbitrary;
+
+#[cfg(feature = "derive")]
+pub use itre_derive::{Encoder, Decoder};
+
+// Used by the code generated by `itre-derive`
+#[cfg(feature = "derive")]
+#[doc(hidden)]
+pub mod __private {
+    pub use bytes::BytesMut;
+}
 pub mod error;
 
 
+/// Protocol version carried in the optional frame header.
+///
+/// Encoding a `ProtocolVersion` writes the frame header; decoding one reads and checks it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProtocolVersion(pub u8);
+
+impl ProtocolVersion {
+    pub const V0_1: ProtocolVersion = ProtocolVersion(consts::PROTOCOL_VERSION_0_1);
+    pub const CURRENT: ProtocolVersion = ProtocolVersion::V0_1;
+}
+
+
+/// A message together with its metadata.
+///
+/// Ids are assigned by the application.
+#[derive(Debug, PartialEq)]
+pub struct Envelope {
+    pub id: u64,
+    pub sender: u64,
+    pub conversation: u64,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    /// Id of the message this one replies to
+    pub reply_to: Option<u64>,
+    pub message: Message,
+}
+
+
+/// Formatting of a range of a rich text.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Style {
+    Bold,                                  // 0
+    Italic,                                // 1
+    Code,                                  // 2
+    Strikethrough,                         // 3
+    Link(String),                          // 4
+    Mention(u64)                           // 5
+}
+
+/// A formatted range of a rich text, in bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub start: u32,
+    /// Exclusive
+    pub end: u32,
+    pub style: Style,
+}
+
+
+/// A file sent along with the messages, see `attachment` for building and reassembling them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attachment {
+    pub filename: String,
+    pub mime_type: String,
+    /// Length of the whole content
+    pub size: u64,
+    pub sha256: [u8; consts::SHA256_LENGTH],
+    pub content: Content,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Content {
+    Inline(Vec<u8>),                       // 0
+    /// The content follows in `count` `Chunk` messages with transfer id `transfer`
+    Chunked { transfer: u64, count: u32 }  // 1
+}
+
+/// A piece of the content of a chunked attachment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub transfer: u64,
+    pub index: u32,
+    pub data: Vec<u8>,
+}
+
+
+/// A geographic position. Coordinates are in microdegrees (degrees times 10^6).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub latitude: i32,
+    pub longitude: i32,
+    /// Radius in meters
+    pub accuracy: Option<u32>,
+    pub name: Option<String>,
+    /// Set for live locations: when the sharing expires, in milliseconds since the Unix epoch
+    pub live_until: Option<u64>,
+}
+
+
 #[derive(Debug, PartialEq)]
 pub enum Emo {
-    Nop,             // 0
-    Laugh,           // 1
-    Cry,             // 2
-    Custom(Vec<u8>)  // 240
+    Nop,                                   // 0
+    Laugh,                                 // 1
+    Cry,                                   // 2
+    Custom(String, Option<(u8, Vec<u8>)>)  // 240
+}
+
+
+/// Control messages carried by `Message::Control`.
+///
+/// Message ids are assigned by the application.
+#[derive(Debug, PartialEq)]
+pub enum Control {
+    Ping,                                      // 1
+    Pong,                                      // 2
+    Ack(u64),                                  // 3
+    TypingStart,                               // 4
+    TypingStop,                                // 5
+    ReadReceipt(u64),                          // 6
+    Close(u16),                                // 7
+    Error { code: u16, description: String }   // 8
+}
+
+impl Control {
+    /// Type code the message is encoded with
+    pub fn code(&self) -> u8 {
+        match *self {
+            Control::Ping => consts::MESSAGE_TYPE_CODE_PING,
+            Control::Pong => consts::MESSAGE_TYPE_CODE_PONG,
+            Control::Ack(_) => consts::MESSAGE_TYPE_CODE_ACK,
+            Control::TypingStart => consts::MESSAGE_TYPE_CODE_TYPING_START,
+            Control::TypingStop => consts::MESSAGE_TYPE_CODE_TYPING_STOP,
+            Control::ReadReceipt(_) => consts::MESSAGE_TYPE_CODE_READ_RECEIPT,
+            Control::Close(_) => consts::MESSAGE_TYPE_CODE_CLOSE,
+            Control::Error { .. } => consts::MESSAGE_TYPE_CODE_ERROR,
+        }
+    }
 }
 
 
 #[derive(Debug, PartialEq)]
 pub enum Message {
-    Nop,                    // 0
-    Text(String),           // 128
-    Emo(Emo),               // 130
-    Image(u8, Vec<u8>),     // 140
-    Compound(Vec<Message>)  // 250
+    Nop,                                       // 0
+    Control(Control),                          // 1 ~ 8
+    Text(String),                              // 128
+    RichText(String, Vec<Span>),               // 129
+    Emo(Emo),                                  // 130
+    Attachment(Attachment),                    // 131
+    Chunk(Chunk),                              // 132
+    Location(Location),                        // 133
+    Image(u8, Vec<u8>),                        // 140
+    Voice {                                    // 141
+        codec: u8,
+        /// In milliseconds
+        duration: u32,
+        waveform: Option<Vec<u8>>,
+        data: Vec<u8>
+    },
+    Compound(Vec<Message>),                    // 250
+    Binary(Vec<u8>),                           // 251
+    /// Encoding panics unless `code` passes `consts::is_extension_type_code`
+    Extension { code: u8, payload: Vec<u8> },  // registered code
+    /// Encoding panics unless the object's code passes `consts::is_extension_type_code`
+    Object(Box<dyn registry::Extension>),      // registered code
+    /// Encoding panics if `code` passes `consts::is_builtin_type_code`
+    Unknown(u8, Vec<u8>)                       // any other enveloped code
 }
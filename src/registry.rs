@@ -0,0 +1,317 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use bytes::BytesMut;
+use super::consts;
+use super::decoder::{Decoder, DecodeContext, Result};
+use super::encoder::Encoder;
+use super::error::Error;
+
+/// Application-defined message that can be carried by `Message::Object`.
+///
+/// Implemented automatically for every `ExtensionType`.
+pub trait Extension: fmt::Debug + Send + Sync {
+    /// Type code the message is encoded with
+    fn code(&self) -> u8;
+    /// Writes the payload of the extension envelope (everything after the payload length)
+    fn encode_payload(&self, buf: &mut BytesMut);
+    /// Number of bytes `encode_payload` writes
+    fn payload_len(&self) -> usize {
+        let mut buf = BytesMut::new();
+        self.encode_payload(&mut buf);
+        buf.len()
+    }
+    fn as_any(&self) -> &dyn Any;
+    fn eq_extension(&self, other: &dyn Extension) -> bool;
+}
+
+/// An application type with its own type code, e.g. a struct deriving `Encoder` and `Decoder`.
+///
+/// Register it with `Registry::register_type` to have it decoded into `Message::Object`.
+/// Its encoding is the payload of the envelope `Message::Object` writes, so it should not
+/// write a type code itself, e.g. derive `Encoder` without `#[itre(code = N)]`; the code
+/// and payload length would otherwise be written twice.
+pub trait ExtensionType: Encoder + Decoder + fmt::Debug + PartialEq + Send + Sync + 'static {
+    const CODE: u8;
+}
+
+impl<T: ExtensionType> Extension for T {
+    fn code(&self) -> u8 {
+        T::CODE
+    }
+
+    fn encode_payload(&self, buf: &mut BytesMut) {
+        self.encode_into(buf);
+    }
+
+    fn payload_len(&self) -> usize {
+        self.encoded_len()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn eq_extension(&self, other: &dyn Extension) -> bool {
+        other.as_any().downcast_ref::<T>() == Some(self)
+    }
+}
+
+impl PartialEq for dyn Extension {
+    fn eq(&self, other: &dyn Extension) -> bool {
+        self.eq_extension(other)
+    }
+}
+
+type DecodeFn = fn(&mut BytesMut, &DecodeContext) -> Result<Box<dyn Extension>>;
+
+fn decode_boxed<T: ExtensionType>(buf: &mut BytesMut,
+                                  ctx: &DecodeContext) -> Result<Box<dyn Extension>> {
+    Ok(Box::new(T::decode_nested(buf, ctx)?))
+}
+
+/// Application-defined message types the decoder should accept.
+///
+/// Only codes in `consts::MESSAGE_TYPE_CODE_EXTENSION_MIN..=MESSAGE_TYPE_CODE_EXTENSION_MAX`
+/// that are not taken by a built-in message can be registered. Hand the registry to the
+/// decoder through `DecodeOptions::registry`.
+#[derive(Debug, Default)]
+pub struct Registry {
+    // `None` for codes registered without a type, which decode into `Message::Extension`
+    types: HashMap<u8, Option<DecodeFn>>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    /// Accepts messages with type code `code`, decoding them into `Message::Extension`.
+    pub fn register(&mut self, code: u8) -> Result<()> {
+        self.insert(code, None)
+    }
+
+    /// Accepts messages of type `T`, decoding them into `Message::Object`.
+    pub fn register_type<T: ExtensionType>(&mut self) -> Result<()> {
+        self.insert(T::CODE, Some(decode_boxed::<T>))
+    }
+
+    pub fn contains(&self, code: u8) -> bool {
+        self.types.contains_key(&code)
+    }
+
+    // Whether `code` was registered with a type, and so decodes into `Message::Object`.
+    pub(crate) fn has_type(&self, code: u8) -> bool {
+        matches!(self.types.get(&code), Some(&Some(_)))
+    }
+
+    fn insert(&mut self, code: u8, decode: Option<DecodeFn>) -> Result<()> {
+        if !consts::is_extension_type_code(code) || self.contains(code) {
+            return Err(Error::TypeCodeUnavailable(code));
+        }
+        self.types.insert(code, decode);
+        Ok(())
+    }
+
+    // Decodes the payload of an extension envelope with type code `code`, which must have
+    // been registered with a type. `ctx` places the payload in the message being decoded.
+    pub(crate) fn decode_object(&self, code: u8, payload: &mut BytesMut,
+                                ctx: &DecodeContext) -> Result<Box<dyn Extension>> {
+        match self.types.get(&code) {
+            Some(&Some(decode)) => decode(payload, ctx),
+            _ => Err(Error::InvalidTypeCode(code))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use bytes::BytesMut;
+    use super::super::Message;
+    use super::super::decoder::{Decoder, DecodeContext, DecodeLimits, DecodeOptions, Result};
+    use super::super::encoder::Encoder;
+    use super::super::error::{Error, Limit};
+    use super::{ExtensionType, Registry};
+
+    #[derive(Debug, PartialEq)]
+    struct Poll {
+        question: String,
+        options: Vec<String>,
+    }
+
+    impl Encoder for Poll {
+        fn encode_into(&self, buf: &mut BytesMut) {
+            self.question.encode_into(buf);
+            self.options.encode_into(buf);
+        }
+    }
+
+    impl Decoder for Poll {
+        fn decode_with(buf: &mut BytesMut, opts: &DecodeOptions) -> Result<Self> {
+            Ok(Poll {
+                question: String::decode_with(buf, opts)?,
+                options: Vec::decode_with(buf, opts)?,
+            })
+        }
+    }
+
+    impl ExtensionType for Poll {
+        const CODE: u8 = 142;
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Quote {
+        msg: Message,
+    }
+
+    impl Encoder for Quote {
+        fn encode_into(&self, buf: &mut BytesMut) {
+            self.msg.encode_into(buf);
+        }
+    }
+
+    impl Decoder for Quote {
+        fn decode_with(buf: &mut BytesMut, opts: &DecodeOptions) -> Result<Self> {
+            Quote::decode_nested(buf, &DecodeContext::new(opts, buf))
+        }
+
+        fn decode_nested(buf: &mut BytesMut, ctx: &DecodeContext) -> Result<Self> {
+            Ok(Quote { msg: Message::decode_nested(buf, ctx)? })
+        }
+    }
+
+    impl ExtensionType for Quote {
+        const CODE: u8 = 143;
+    }
+
+    fn options() -> DecodeOptions {
+        let mut registry = Registry::new();
+        registry.register_type::<Poll>().unwrap();
+        registry.register_type::<Quote>().unwrap();
+        registry.register(200).unwrap();
+        DecodeOptions { registry: Some(Arc::new(registry)), ..DecodeOptions::default() }
+    }
+
+    // `depth` quotes nested in one another, quoting a Nop message.
+    fn nested_quotes(depth: usize) -> BytesMut {
+        let mut buf = BytesMut::new();
+        for level in (0..depth).rev() {
+            // Type code and payload length of each quote inside, and the Nop
+            let len = level * 5 + 1;
+            buf.extend_from_slice(&[Quote::CODE]);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+        buf.extend_from_slice(&[0]);
+        buf
+    }
+
+    #[test]
+    fn register_unavailable() {
+        let mut registry = Registry::new();
+        for &code in &[0, 127, 128, 129, 130, 131, 132, 133, 140, 141, 250, 255] {
+            match registry.register(code) {
+                Err(Error::TypeCodeUnavailable(c)) => assert_eq!(c, code),
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+        registry.register(134).unwrap();
+        match registry.register(134) {
+            Err(Error::TypeCodeUnavailable(134)) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn encode_extension() {
+        let msg = Message::Extension { code: 200, payload: b"raw".to_vec() };
+        let mut buf = BytesMut::new();
+        msg.encode_into(&mut buf);
+        // 1 byte type code
+        // 4 byte payload length
+        assert_eq!(&buf[..], b"\xc8\x00\x00\x00\x03raw");
+
+        let msg = Message::Object(Box::new(Poll {
+            question: String::from("?"),
+            options: vec![String::from("y")],
+        }));
+        let mut buf = BytesMut::new();
+        msg.encode_into(&mut buf);
+        assert_eq!(&buf[..], b"\x8e\x00\x00\x00\x0a\x00\x01?\x00\x00\x00\x01\x00\x01y");
+    }
+
+    #[test]
+    fn roundtrip_registered() {
+        let msg = Message::Compound(vec![
+            Message::Object(Box::new(Poll {
+                question: String::from("Lunch?"),
+                options: vec![String::from("Noodles"), String::from("寿司")],
+            })),
+            Message::Extension { code: 200, payload: vec![1, 2, 3] },
+        ]);
+        let mut buf = BytesMut::new();
+        msg.encode_into(&mut buf);
+        let decoded = Message::decode_with(&mut buf, &options()).unwrap();
+        assert_eq!(decoded, msg);
+        assert!(buf.is_empty());
+        match decoded {
+            Message::Compound(ref msgs) => match msgs[0] {
+                Message::Object(ref obj) => {
+                    let poll = obj.as_any().downcast_ref::<Poll>().unwrap();
+                    assert_eq!(poll.options.len(), 2);
+                },
+                ref other => panic!("unexpected message: {:?}", other)
+            },
+            ref other => panic!("unexpected message: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn decode_nested_limits() {
+        let mut buf = nested_quotes(3);
+        let decoded = Message::decode_with(&mut buf, &options()).unwrap();
+        let mut quoted = Message::Nop;
+        for _ in 0..3 {
+            quoted = Message::Object(Box::new(Quote { msg: quoted }));
+        }
+        assert_eq!(decoded, quoted);
+
+        // Registered types nest like compound messages, rather than restarting the limits
+        let opts = DecodeOptions {
+            limits: DecodeLimits { max_depth: 4, ..DecodeLimits::default() },
+            ..options()
+        };
+        let mut buf = BytesMut::from(&b"\xfa\x01\x8f\x00\x00\x00\x08\xfa\x01\x8f\x00\x00\x00\x01\x00"[..]);
+        assert!(Message::decode_with(&mut buf, &opts).is_ok());
+        let mut buf = nested_quotes(5);
+        match Message::decode_with(&mut buf, &opts).map_err(Error::into_kind) {
+            Err(Error::LimitExceeded(Limit::Depth)) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+        let opts = DecodeOptions {
+            limits: DecodeLimits { max_total_bytes: 20, ..DecodeLimits::default() },
+            ..options()
+        };
+        let mut buf = nested_quotes(4);
+        match Message::decode_with(&mut buf, &opts).map_err(Error::into_kind) {
+            Err(Error::LimitExceeded(Limit::TotalBytes)) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+
+        // Deep enough to overflow the stack without the limits
+        let mut buf = nested_quotes(200_000);
+        match Message::decode_with(&mut buf, &options()).map_err(Error::into_kind) {
+            Err(Error::LimitExceeded(Limit::Depth)) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn decode_unregistered() {
+        let mut buf = BytesMut::from(&b"\xc9\x00\x00\x00\x01?"[..]);
+        assert_eq!(
+            Message::decode_with(&mut buf, &options()).unwrap(),
+            Message::Unknown(0xc9, b"?".to_vec())
+        );
+    }
+}
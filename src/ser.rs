@@ -0,0 +1,344 @@
+//! Serde `Serializer` that turns Rust values into `Message` trees.
+//!
+//! Mapping rules (the inverse of the ones in `de`):
+//!
+//! | Rust / serde data model                   | Message                                       |
+//! |-------------------------------------------|-----------------------------------------------|
+//! | `bool`, integers, floats, `char`          | `Text` holding the value's `Display` output   |
+//! | `str`, `String`                           | `Text`                                        |
+//! | byte arrays (`serialize_bytes`)           | `Binary`                                      |
+//! | `None`                                    | `Nop`                                         |
+//! | `Some(v)`                                 | `Compound([v])`                               |
+//! | `()`, unit struct                         | `Nop`                                         |
+//! | unit variant                              | `Text(variant name)`                          |
+//! | newtype struct                            | the wrapped value                             |
+//! | newtype variant                           | `Compound([Text(variant name), value])`       |
+//! | sequence, tuple, tuple struct             | `Compound(elements)`                          |
+//! | map                                       | `Compound` of `Compound([key, value])`        |
+//! | struct                                    | `Compound(field values in declaration order)` |
+//! | tuple or struct variant                   | `Compound([Text(variant name), Compound(fields)])` |
+//!
+//! Field names are not encoded, so structs must be deserialized into the same field order.
+//!
+//! Byte arrays, e.g. fields marked `#[serde(with = "serde_bytes")]`, are written as a single
+//! payload. `Vec<u8>` and other sequences of bytes are sequences like any other. Long
+//! sequences and strings may exceed the default `DecodeLimits`; decode them with
+//! `de::from_bytes_with`.
+
+use std::fmt::Display;
+use bytes::BytesMut;
+use serde::ser::{self, Serialize};
+use super::Message;
+use super::encoder::Encoder;
+use super::error::SerdeError;
+
+pub type Result<T> = std::result::Result<T, SerdeError>;
+
+/// Converts `value` into a `Message` tree.
+pub fn to_message<T: Serialize + ?Sized>(value: &T) -> Result<Message> {
+    value.serialize(Serializer)
+}
+
+/// Converts `value` into a `Message` tree and encodes it.
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<BytesMut> {
+    let mut buf = BytesMut::new();
+    to_message(value)?.encode_into(&mut buf);
+    Ok(buf)
+}
+
+pub struct Serializer;
+
+fn text<T: Display>(value: T) -> Result<Message> {
+    Ok(Message::Text(value.to_string()))
+}
+
+fn variant(name: &'static str, value: Message) -> Message {
+    Message::Compound(vec![Message::Text(String::from(name)), value])
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = Message;
+    type Error = SerdeError;
+
+    type SerializeSeq = SerializeVec;
+    type SerializeTuple = SerializeVec;
+    type SerializeTupleStruct = SerializeVec;
+    type SerializeTupleVariant = SerializeVariant;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeVec;
+    type SerializeStructVariant = SerializeVariant;
+
+    fn serialize_bool(self, v: bool) -> Result<Message> { text(v) }
+    fn serialize_i8(self, v: i8) -> Result<Message> { text(v) }
+    fn serialize_i16(self, v: i16) -> Result<Message> { text(v) }
+    fn serialize_i32(self, v: i32) -> Result<Message> { text(v) }
+    fn serialize_i64(self, v: i64) -> Result<Message> { text(v) }
+    fn serialize_i128(self, v: i128) -> Result<Message> { text(v) }
+    fn serialize_u8(self, v: u8) -> Result<Message> { text(v) }
+    fn serialize_u16(self, v: u16) -> Result<Message> { text(v) }
+    fn serialize_u32(self, v: u32) -> Result<Message> { text(v) }
+    fn serialize_u64(self, v: u64) -> Result<Message> { text(v) }
+    fn serialize_u128(self, v: u128) -> Result<Message> { text(v) }
+    fn serialize_f32(self, v: f32) -> Result<Message> { text(v) }
+    fn serialize_f64(self, v: f64) -> Result<Message> { text(v) }
+    fn serialize_char(self, v: char) -> Result<Message> { text(v) }
+    fn serialize_str(self, v: &str) -> Result<Message> { text(v) }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Message> {
+        Ok(Message::Binary(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<Message> {
+        Ok(Message::Nop)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Message> {
+        Ok(Message::Compound(vec![to_message(value)?]))
+    }
+
+    fn serialize_unit(self) -> Result<Message> {
+        Ok(Message::Nop)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Message> {
+        Ok(Message::Nop)
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32,
+                              variant: &'static str) -> Result<Message> {
+        text(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str,
+                                                       value: &T) -> Result<Message> {
+        to_message(value)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _index: u32,
+                                                        variant: &'static str,
+                                                        value: &T) -> Result<Message> {
+        Ok(self::variant(variant, to_message(value)?))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeVec> {
+        Ok(SerializeVec { items: Vec::with_capacity(len.unwrap_or(0)) })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeVec> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<SerializeVec> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, variant: &'static str,
+                               len: usize) -> Result<SerializeVariant> {
+        Ok(SerializeVariant { name: variant, items: Vec::with_capacity(len) })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeMap> {
+        Ok(SerializeMap { entries: Vec::with_capacity(len.unwrap_or(0)), key: None })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeVec> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, variant: &'static str,
+                                len: usize) -> Result<SerializeVariant> {
+        Ok(SerializeVariant { name: variant, items: Vec::with_capacity(len) })
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+pub struct SerializeVec {
+    items: Vec<Message>,
+}
+
+impl ser::SerializeSeq for SerializeVec {
+    type Ok = Message;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.items.push(to_message(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Message> {
+        Ok(Message::Compound(self.items))
+    }
+}
+
+impl ser::SerializeTuple for SerializeVec {
+    type Ok = Message;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Message> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeVec {
+    type Ok = Message;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Message> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeStruct for SerializeVec {
+    type Ok = Message;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, _key: &'static str,
+                                              value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Message> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+pub struct SerializeVariant {
+    name: &'static str,
+    items: Vec<Message>,
+}
+
+impl ser::SerializeTupleVariant for SerializeVariant {
+    type Ok = Message;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.items.push(to_message(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Message> {
+        Ok(variant(self.name, Message::Compound(self.items)))
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeVariant {
+    type Ok = Message;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, _key: &'static str,
+                                              value: &T) -> Result<()> {
+        ser::SerializeTupleVariant::serialize_field(self, value)
+    }
+
+    fn end(self) -> Result<Message> {
+        ser::SerializeTupleVariant::end(self)
+    }
+}
+
+pub struct SerializeMap {
+    entries: Vec<Message>,
+    key: Option<Message>,
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = Message;
+    type Error = SerdeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        self.key = Some(to_message(key)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let key = match self.key.take() {
+            Some(key) => key,
+            None => return Err(ser::Error::custom("map value serialized before its key"))
+        };
+        self.entries.push(Message::Compound(vec![key, to_message(value)?]));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Message> {
+        Ok(Message::Compound(self.entries))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use serde::Serialize;
+    use super::super::Message;
+    use super::to_message;
+
+    #[derive(Serialize)]
+    struct Unit;
+
+    #[derive(Serialize)]
+    enum Shape {
+        Empty,
+        Circle(u32),
+        Rect { w: u8, h: u8 },
+    }
+
+    fn text(s: &str) -> Message {
+        Message::Text(String::from(s))
+    }
+
+    #[test]
+    fn serialize_scalars() {
+        assert_eq!(to_message(&true).unwrap(), text("true"));
+        assert_eq!(to_message(&-42i32).unwrap(), text("-42"));
+        assert_eq!(to_message(&1.5f64).unwrap(), text("1.5"));
+        assert_eq!(to_message(&'字').unwrap(), text("字"));
+        assert_eq!(to_message("ITRE").unwrap(), text("ITRE"));
+        assert_eq!(to_message(&()).unwrap(), Message::Nop);
+        assert_eq!(to_message(&Unit).unwrap(), Message::Nop);
+        assert_eq!(
+            to_message(&serde_bytes::Bytes::new(b"ab")).unwrap(),
+            Message::Binary(b"ab".to_vec())
+        );
+    }
+
+    #[test]
+    fn serialize_containers() {
+        assert_eq!(to_message(&None::<u8>).unwrap(), Message::Nop);
+        assert_eq!(to_message(&Some(1u8)).unwrap(), Message::Compound(vec![text("1")]));
+        assert_eq!(
+            to_message(&(1u8, "a")).unwrap(),
+            Message::Compound(vec![text("1"), text("a")])
+        );
+        let mut map = BTreeMap::new();
+        map.insert("k", 2u8);
+        assert_eq!(
+            to_message(&map).unwrap(),
+            Message::Compound(vec![Message::Compound(vec![text("k"), text("2")])])
+        );
+    }
+
+    #[test]
+    fn serialize_enum() {
+        assert_eq!(to_message(&Shape::Empty).unwrap(), text("Empty"));
+        assert_eq!(
+            to_message(&Shape::Circle(3)).unwrap(),
+            Message::Compound(vec![text("Circle"), text("3")])
+        );
+        assert_eq!(
+            to_message(&Shape::Rect { w: 1, h: 2 }).unwrap(),
+            Message::Compound(vec![
+                text("Rect"),
+                Message::Compound(vec![text("1"), text("2")])
+            ])
+        );
+    }
+}
@@ -0,0 +1,557 @@
+use bytes::BytesMut;
+use byteorder::{ByteOrder, BigEndian};
+use super::consts;
+use super::{Message, ProtocolVersion};
+use super::decoder::{type_name, Decoder, DecodeOptions, Result};
+use super::error::{Context, Error, Limit};
+
+// A compound message whose nested messages are still being scanned.
+struct Pending {
+    // Nested messages left in the current slice
+    remaining: usize,
+    // Whether another slice (and its length byte) follows the current one
+    more: bool,
+    // Nested messages in all slices so far
+    children: usize,
+}
+
+/// Incremental decoder for messages that arrive in pieces, e.g. from partial network reads.
+///
+/// Bytes are left in the buffer until a whole message is available. Progress made while
+/// scanning an incomplete message is remembered, so each call resumes after the last whole
+/// message nested in it, even deep inside nested compound messages. Of an incomplete
+/// non-compound message, only the length fields are read again, e.g. one per slice of a
+/// long text.
+///
+/// `DecodeOptions::limits` are enforced while scanning, so a hostile peer cannot make the
+/// buffer grow past `DecodeLimits::max_total_bytes`.
+///
+/// With `DecodeOptions::frame_header` set, the stream must start with a frame header, which
+/// is checked against `DecodeOptions::versions` before the first message.
+///
+/// Errors are wrapped in `Error::Context`, like from `Message::decode_with`; use
+/// `Error::kind` to match on them. Offsets are relative to the start of the message (or of
+/// the frame header) and point where decoding stopped, i.e. just past an invalid code, not
+/// at the offending byte. Errors found while scanning an incomplete message carry the same
+/// path and offset as decoding it would, except that too many nested messages are reported
+/// right after the slice length announcing them, and an incomplete message exceeding
+/// `DecodeLimits::max_total_bytes` at the number of bytes buffered.
+///
+/// After an error the stream cannot be resynchronized and the decoder should be dropped.
+pub struct StreamDecoder {
+    opts: DecodeOptions,
+    // Read from the frame header
+    version: Option<ProtocolVersion>,
+    // Length of the prefix of the buffer known to belong to the next message
+    scanned: usize,
+    // Enclosing compound messages of the position `scanned`, innermost last
+    pending: Vec<Pending>,
+    // Whether the byte at `scanned` is the length byte of the innermost compound
+    need_length: bool,
+}
+
+impl StreamDecoder {
+    pub fn new() -> StreamDecoder {
+        StreamDecoder::with_options(DecodeOptions::default())
+    }
+
+    pub fn with_options(opts: DecodeOptions) -> StreamDecoder {
+        StreamDecoder {
+            opts,
+            version: None,
+            scanned: 0,
+            pending: Vec::new(),
+            need_length: false,
+        }
+    }
+
+    /// Decodes the next message from the front of `buf`.
+    ///
+    /// Returns `Ok(None)` without consuming anything if `buf` does not hold a whole message
+    /// yet. Call again with the same buffer once more bytes have been appended to it.
+    /// An expected frame header is consumed as soon as it is complete.
+    pub fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Message>> {
+        if self.opts.frame_header && self.version.is_none() {
+            let len = consts::FRAME_MAGIC.len() + 1;
+            if buf.len() < len {
+                return Ok(None);
+            }
+            let mut header = buf.split_to(len);
+            let version = ProtocolVersion::decode_with(&mut header, &self.opts)
+                .map_err(|e| e.at(len - header.len()))?;
+            self.version = Some(version);
+        }
+        let done = self.scan(buf)?;
+        // Until the message is complete, everything in `buf` belongs to it
+        let len = if done { self.scanned } else { buf.len() };
+        if len > self.opts.limits.max_total_bytes {
+            return Err(Error::LimitExceeded(Limit::TotalBytes).at(len));
+        }
+        if !done {
+            return Ok(None);
+        }
+        let mut frame = buf.split_to(self.scanned);
+        self.scanned = 0;
+        let msg = Message::decode_with(&mut frame, &self.opts)?;
+        Ok(Some(msg))
+    }
+
+    /// Protocol version of the stream, once its frame header has been decoded.
+    pub fn version(&self) -> Option<ProtocolVersion> {
+        self.version
+    }
+
+    // Advances `scanned` as far as the bytes in `buf` allow.
+    // Returns whether a whole message has been scanned.
+    fn scan(&mut self, buf: &BytesMut) -> Result<bool> {
+        loop {
+            let bytes = &buf[self.scanned..];
+            if bytes.is_empty() {
+                return Ok(false);
+            }
+            if self.need_length {
+                self.need_length = false;
+                self.scanned += 1;
+                let started = if bytes[0] == consts::COMPOUND_OVERFLOW_FLAG {
+                    self.start_slice(consts::COMPOUND_SLICE_MAX_LENGTH_S, true)
+                } else if bytes[0] > 0 {
+                    self.start_slice(bytes[0] as usize, false)
+                } else {
+                    self.pending.pop();
+                    if self.nested_done() {
+                        return Ok(true);
+                    }
+                    Ok(())
+                };
+                if let Err(e) = started {
+                    let e = e.within(type_name(consts::MESSAGE_TYPE_CODE_COMPOUND), 0);
+                    return Err(self.context(e, self.pending.len() - 1));
+                }
+                continue;
+            }
+            if bytes[0] == consts::MESSAGE_TYPE_CODE_COMPOUND {
+                if self.pending.len() >= self.opts.limits.max_depth {
+                    let e = Error::LimitExceeded(Limit::Depth)
+                        .within(type_name(consts::MESSAGE_TYPE_CODE_COMPOUND), 1);
+                    return Err(self.context(e, self.pending.len()));
+                }
+                self.scanned += 1;
+                self.pending.push(Pending { remaining: 0, more: true, children: 0 });
+                self.need_length = true;
+                continue;
+            }
+            let len = leaf_len(bytes).map_err(|e| self.context(e, self.pending.len()))?;
+            match len {
+                Some(len) => {
+                    self.scanned += len;
+                    if self.nested_done() {
+                        return Ok(true);
+                    }
+                },
+                None => return Ok(false)
+            }
+        }
+    }
+
+    fn start_slice(&mut self, remaining: usize, more: bool) -> Result<()> {
+        let top = self.pending.last_mut().unwrap();
+        top.remaining = remaining;
+        top.more = more;
+        top.children += remaining;
+        if top.children > self.opts.limits.max_children {
+            return Err(Error::LimitExceeded(Limit::Children));
+        }
+        Ok(())
+    }
+
+    // Adds the position of `scanned` to an error found in the message starting there, given
+    // the path and offset within that message: the path through the outermost `depth`
+    // pending compound messages and the offset from the start of the outermost one.
+    fn context(&self, error: Error, depth: usize) -> Error {
+        let mut path = Vec::new();
+        for pending in &self.pending[..depth] {
+            path.push(type_name(consts::MESSAGE_TYPE_CODE_COMPOUND));
+            path.push(format!("[{}]", pending.children - pending.remaining));
+        }
+        let ctx = match error.at(0) {
+            Error::Context(ctx) => *ctx,
+            _ => unreachable!()
+        };
+        path.extend(ctx.path);
+        Error::Context(Box::new(Context {
+            path,
+            offset: self.scanned + ctx.offset,
+            error: ctx.error,
+        }))
+    }
+
+    // Records that a message just ended at `scanned`.
+    // Returns whether that completes the outermost message.
+    fn nested_done(&mut self) -> bool {
+        loop {
+            match self.pending.last_mut() {
+                None => return true,
+                Some(top) => {
+                    top.remaining -= 1;
+                    if top.remaining > 0 {
+                        return false;
+                    }
+                    if top.more {
+                        self.need_length = true;
+                        return false;
+                    }
+                }
+            }
+            self.pending.pop();
+        }
+    }
+}
+
+impl Default for StreamDecoder {
+    fn default() -> Self {
+        StreamDecoder::new()
+    }
+}
+
+// Length of the non-compound message at the front of `bytes`, or `None` if it is incomplete.
+// Errors carry their path and offset within the message.
+fn leaf_len(bytes: &[u8]) -> Result<Option<usize>> {
+    let type_code = bytes[0];
+    let body = &bytes[1..];
+    let len = match type_code {
+        consts::MESSAGE_TYPE_CODE_NOP => Some(0),
+        consts::MESSAGE_TYPE_CODE_TEXT => text_len(body),
+        consts::MESSAGE_TYPE_CODE_EMO => emo_len(body).map_err(|e| e.within(type_name(type_code), 1))?,
+        consts::MESSAGE_TYPE_CODE_IMAGE => {
+            image_len(body).map_err(|e| e.at(1).within(type_name(type_code), 1))?
+        },
+        _ => envelope_len(body)
+    };
+    Ok(len.map(|len| 1 + len))
+}
+
+// Lengths are compared with what is left of `bytes` rather than added to positions, which
+// could overflow on 32-bit targets.
+fn text_len(bytes: &[u8]) -> Option<usize> {
+    let mut pos = 0;
+    loop {
+        if bytes.len() - pos < 2 {
+            return None;
+        }
+        let len = BigEndian::read_u16(&bytes[pos..pos + 2]);
+        let slice_len = if len == consts::TEXT_OVERFLOW_FLAG {
+            consts::TEXT_SLICE_MAX_LENGTH_S
+        } else {
+            len as usize
+        };
+        if bytes.len() - pos - 2 < slice_len {
+            return None;
+        }
+        pos += 2 + slice_len;
+        if len != consts::TEXT_OVERFLOW_FLAG {
+            return Some(pos);
+        }
+    }
+}
+
+// Fails with the image format, found in the first byte.
+fn image_len(bytes: &[u8]) -> Result<Option<usize>> {
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+    match bytes[0] {
+        consts::MESSAGE_IMAGE_FORMAT_PNG |
+        consts::MESSAGE_IMAGE_FORMAT_JPEG |
+        consts::MESSAGE_IMAGE_FORMAT_GIF |
+        consts::MESSAGE_IMAGE_FORMAT_WEBP => {},
+        format => return Err(Error::InvalidImageFormat(format))
+    }
+    // Format and data length
+    if bytes.len() < 5 {
+        return Ok(None);
+    }
+    let len = BigEndian::read_u32(&bytes[1..5]) as usize;
+    if bytes.len() - 5 < len {
+        return Ok(None);
+    }
+    Ok(Some(5 + len))
+}
+
+fn envelope_len(bytes: &[u8]) -> Option<usize> {
+    if bytes.len() < consts::ENVELOPE_HEADER_LENGTH {
+        return None;
+    }
+    let len = BigEndian::read_u32(bytes) as usize;
+    if bytes.len() - consts::ENVELOPE_HEADER_LENGTH < len {
+        return None;
+    }
+    Some(consts::ENVELOPE_HEADER_LENGTH + len)
+}
+
+// Errors carry their offset in `bytes`.
+fn emo_len(bytes: &[u8]) -> Result<Option<usize>> {
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+    match bytes[0] {
+        consts::MESSAGE_EMO_CODE_NOP |
+        consts::MESSAGE_EMO_CODE_LAUGH |
+        consts::MESSAGE_EMO_CODE_CRY => Ok(Some(1)),
+        consts::MESSAGE_EMO_CODE_CUSTOM => {
+            let id_len = match text_len(&bytes[1..]) {
+                Some(len) => len,
+                None => return Ok(None)
+            };
+            let slot = &bytes[1 + id_len..];
+            if slot.is_empty() {
+                return Ok(None);
+            }
+            let slot_len = match slot[0] {
+                consts::MESSAGE_TYPE_CODE_NOP => Some(0),
+                consts::MESSAGE_TYPE_CODE_IMAGE => {
+                    image_len(&slot[1..]).map_err(|e| e.at(1 + id_len + 1 + 1))?
+                },
+                type_code => return Err(Error::InvalidTypeCode(type_code).at(1 + id_len + 1))
+            };
+            Ok(slot_len.map(|len| 1 + id_len + 1 + len))
+        },
+        emo_code => Err(Error::InvalidEmoCode(emo_code).at(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use super::consts;
+    use std::sync::Arc;
+    use super::{Message, ProtocolVersion, StreamDecoder};
+    use super::super::Emo;
+    use super::super::decoder::{Decoder, DecodeOptions, DecodeLimits};
+    use super::super::registry::Registry;
+    use super::super::encoder::Encoder;
+    use super::super::error::{Error, Limit};
+
+    fn sample_messages() -> Vec<Message> {
+        vec![
+            Message::Nop,
+            Message::Text(String::from("ITRE解码测试")),
+            Message::Compound(vec![
+                Message::Emo(Emo::Laugh),
+                Message::Compound(vec![]),
+                Message::Compound(
+                    (0..300).map(|i| Message::Compound(vec![Message::Text(i.to_string())])).collect()
+                ),
+                Message::Emo(Emo::Custom(
+                    String::from("pack/wave"),
+                    Some((consts::MESSAGE_IMAGE_FORMAT_GIF, b"GIF8".to_vec()))
+                )),
+            ]),
+            Message::Image(consts::MESSAGE_IMAGE_FORMAT_PNG, vec![0x89; 70000]),
+            Message::Text("测".repeat(30000)),
+        ]
+    }
+
+    #[test]
+    fn decode_in_chunks() {
+        let msgs = sample_messages();
+        let mut encoded = BytesMut::new();
+        for msg in &msgs {
+            msg.encode_into(&mut encoded);
+        }
+        for &chunk_size in &[1, 7, 4096, encoded.len()] {
+            let mut decoder = StreamDecoder::new();
+            let mut buf = BytesMut::new();
+            let mut decoded = Vec::new();
+            for chunk in encoded.chunks(chunk_size) {
+                buf.extend_from_slice(chunk);
+                loop {
+                    let len = buf.len();
+                    match decoder.decode(&mut buf).unwrap() {
+                        Some(msg) => decoded.push(msg),
+                        None => {
+                            assert_eq!(buf.len(), len);
+                            break;
+                        }
+                    }
+                }
+            }
+            assert_eq!(decoded, msgs);
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn decode_invalid_emo_code() {
+        let mut decoder = StreamDecoder::new();
+        let mut buf = BytesMut::from(&b"\xfa\x02\x00"[..]);
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"\x82\x7f");
+        match decoder.decode(&mut buf) {
+            Err(e) => {
+                assert_eq!(e.to_string(), "compound[1].emo at offset 5: invalid emo code 127");
+                match e.into_kind() {
+                    Error::InvalidEmoCode(0x7f) => {},
+                    other => panic!("unexpected error: {:?}", other)
+                }
+            },
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn decode_errors_like_message() {
+        // Errors found while scanning read the same as the ones of `Message::decode_from`,
+        // even though the messages are incomplete
+        let cases: Vec<&[u8]> = vec![
+            b"\x8c\x07\x00",
+            b"\xfa\x01\x82\x7f",
+            b"\xfa\x02\x80\x00\x01a\xfa\x01\x82\x03\x00\x04pack\x8d",
+            b"\xfa\x03\x00\xfa\x01\x00\x82\x03\x00\x00\x8c\x09\x00\x00",
+        ];
+        for bytes in cases {
+            let expected = Message::decode_from(&mut BytesMut::from(bytes)).unwrap_err();
+            assert!(!matches!(expected.kind(), Error::UnexpectedEof(_)));
+            let mut buf = BytesMut::from(bytes);
+            let e = StreamDecoder::new().decode(&mut buf).unwrap_err();
+            assert!(e.context().is_some());
+            assert_eq!(e.to_string(), expected.to_string());
+        }
+        let opts = DecodeOptions {
+            limits: DecodeLimits { max_depth: 2, ..DecodeLimits::default() },
+            ..DecodeOptions::default()
+        };
+        let bytes = &b"\xfa\x02\x00\xfa\x01\xfa\x01"[..];
+        let expected = Message::decode_with(&mut BytesMut::from(bytes), &opts).unwrap_err();
+        let mut buf = BytesMut::from(bytes);
+        let e = StreamDecoder::with_options(opts).decode(&mut buf).unwrap_err();
+        assert_eq!(e.to_string(), expected.to_string());
+    }
+
+    #[test]
+    fn decode_max_lengths() {
+        // The largest lengths a message can announce wait for more bytes
+        let encoded: [&[u8]; 3] = [
+            b"\x10\xff\xff\xff\xff",
+            b"\x8c\x00\xff\xff\xff\xff",
+            b"\x80\xff\xff\x00",
+        ];
+        for bytes in &encoded {
+            let mut buf = BytesMut::from(*bytes);
+            assert!(StreamDecoder::new().decode(&mut buf).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn decode_unknown_in_chunks() {
+        let mut encoded = BytesMut::from(&b"\xfa\x03\x7f\x00\x00\x00\x02ab\xfc\x00\x00\x00\x00\x00"[..]);
+        let mut decoder = StreamDecoder::new();
+        let mut buf = BytesMut::new();
+        for &b in &encoded[..encoded.len() - 1] {
+            buf.extend_from_slice(&[b]);
+            assert!(decoder.decode(&mut buf).unwrap().is_none());
+        }
+        buf.extend_from_slice(&encoded[encoded.len() - 1..]);
+        let msg = decoder.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(msg, Message::Compound(vec![
+            Message::Unknown(0x7f, b"ab".to_vec()),
+            Message::Unknown(0xfc, vec![]),
+            Message::Nop,
+        ]));
+        let mut reencoded = BytesMut::new();
+        msg.encode_into(&mut reencoded);
+        assert_eq!(reencoded, encoded.split());
+    }
+
+    #[test]
+    fn decode_registered_extension() {
+        let mut registry = Registry::new();
+        registry.register(142).unwrap();
+        let opts = DecodeOptions { registry: Some(Arc::new(registry)), ..DecodeOptions::default() };
+        let msg = Message::Compound(vec![Message::Extension { code: 142, payload: vec![0xfa; 300] }]);
+        let mut encoded = BytesMut::new();
+        msg.encode_into(&mut encoded);
+
+        let mut decoder = StreamDecoder::with_options(opts);
+        let mut buf = BytesMut::new();
+        for &b in &encoded[..encoded.len() - 1] {
+            buf.extend_from_slice(&[b]);
+            assert!(decoder.decode(&mut buf).unwrap().is_none());
+        }
+        buf.extend_from_slice(&encoded[encoded.len() - 1..]);
+        assert_eq!(decoder.decode(&mut buf).unwrap(), Some(msg));
+
+        // Without the registry the same bytes decode into an unknown message
+        let mut buf = encoded.clone();
+        assert_eq!(
+            StreamDecoder::new().decode(&mut buf).unwrap(),
+            Some(Message::Compound(vec![Message::Unknown(142, vec![0xfa; 300])]))
+        );
+    }
+
+    #[test]
+    fn decode_frame_header() {
+        let opts = DecodeOptions { frame_header: true, ..DecodeOptions::default() };
+        let msgs = sample_messages();
+        let mut encoded = BytesMut::new();
+        ProtocolVersion::CURRENT.encode_into(&mut encoded);
+        for msg in &msgs {
+            msg.encode_into(&mut encoded);
+        }
+        let mut decoder = StreamDecoder::with_options(opts.clone());
+        let mut buf = BytesMut::new();
+        let mut decoded = Vec::new();
+        for chunk in encoded.chunks(3) {
+            buf.extend_from_slice(chunk);
+            while let Some(msg) = decoder.decode(&mut buf).unwrap() {
+                decoded.push(msg);
+            }
+        }
+        assert_eq!(decoded, msgs);
+        assert_eq!(decoder.version(), Some(ProtocolVersion::CURRENT));
+
+        let mut buf = BytesMut::from(&b"ITRE\x02\x00"[..]);
+        match StreamDecoder::with_options(opts.clone()).decode(&mut buf).map_err(Error::into_kind) {
+            Err(Error::UnsupportedVersion(2)) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+        // A message where the header should be
+        let mut buf = BytesMut::from(&b"\x80\x00\x02Hi"[..]);
+        match StreamDecoder::with_options(opts).decode(&mut buf).map_err(Error::into_kind) {
+            Err(Error::InvalidFrameMagic(_)) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn decode_limits() {
+        let opts = DecodeOptions {
+            limits: DecodeLimits { max_total_bytes: 1000, max_children: 300, ..DecodeLimits::default() },
+            ..DecodeOptions::default()
+        };
+        let cases: Vec<(Vec<u8>, Limit)> = vec![
+            // Nesting deeper than the default of 32 fails as soon as it is seen
+            (b"\xfa\x01".repeat(33), Limit::Depth),
+            // An image too large to ever fit is not buffered
+            (b"\x8c\x00\xff\xff\xff\xff".iter().cloned().chain(vec![0; 2000]).collect(), Limit::TotalBytes),
+            // 254 + 254 children
+            (b"\xfa\xff".iter().cloned().chain(vec![0; 254]).chain(vec![0xfe]).collect(), Limit::Children),
+        ];
+        for (bytes, limit) in cases {
+            let mut decoder = StreamDecoder::with_options(opts.clone());
+            let mut buf = BytesMut::new();
+            let mut result = Ok(None);
+            for chunk in bytes.chunks(100) {
+                buf.extend_from_slice(chunk);
+                result = decoder.decode(&mut buf);
+                if result.is_err() {
+                    break;
+                }
+                assert!(buf.len() <= 1000);
+            }
+            match result.map_err(Error::into_kind) {
+                Err(Error::LimitExceeded(l)) => assert_eq!(l, limit),
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+    }
+}
@@ -0,0 +1,490 @@
+//! Messages decoded without copying their texts and bytes out of the input.
+//!
+//! `MessageRef` borrows from a `&[u8]`, `SharedMessage` shares the storage of a `Bytes`.
+//! Texts and bytes written in several slices (see the crate docs) are the exception: they
+//! have to be joined into a new buffer. Both convert into an owned `Message` with
+//! `into_message`.
+//!
+//! Decoding is done by the same code as `Message::decode_with`, so it accepts and rejects the
+//! same input, with the same errors.
+
+use std;
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Deref;
+use bytes::{Buf, Bytes};
+use super::consts;
+use super::{Message, Control, Emo, Span, Attachment, Content, Chunk, Location};
+use super::decoder::{decode_nested_message, DecodeOptions, Input, Nesting, Result};
+use super::error::Error;
+use super::registry::Extension;
+
+/// A message whose texts are `T`s and whose bytes are `B`s, see `MessageRef` and
+/// `SharedMessage`.
+///
+/// Mirrors `Message`, but for control messages and locations, which are small enough to
+/// be decoded into their owned types.
+#[derive(Debug, PartialEq)]
+pub enum MessageView<T, B> {
+    Nop,                                       // 0
+    Control(Control),                          // 1 ~ 8
+    Text(T),                                   // 128
+    RichText(T, Vec<Span>),                    // 129
+    Emo(EmoView<T, B>),                        // 130
+    Attachment(AttachmentView<T, B>),          // 131
+    Chunk(ChunkView<B>),                       // 132
+    Location(Location),                        // 133
+    Image(u8, B),                              // 140
+    Voice {                                    // 141
+        codec: u8,
+        /// In milliseconds
+        duration: u32,
+        waveform: Option<B>,
+        data: B
+    },
+    Compound(Vec<MessageView<T, B>>),          // 250
+    Binary(B),                                 // 251
+    Extension { code: u8, payload: B },        // registered code
+    /// Decoded from a copy of the payload, like any `ExtensionType`
+    Object(Box<dyn Extension>),                // registered code
+    Unknown(u8, B)                             // any other enveloped code
+}
+
+/// A message borrowing its texts and bytes from the input.
+pub type MessageRef<'a> = MessageView<Cow<'a, str>, Cow<'a, [u8]>>;
+
+/// A message sharing the storage of the input `Bytes` for its texts and bytes.
+pub type SharedMessage = MessageView<SharedStr, Bytes>;
+
+#[derive(Debug, PartialEq)]
+pub enum EmoView<T, B> {
+    Nop,                                   // 0
+    Laugh,                                 // 1
+    Cry,                                   // 2
+    Custom(T, Option<(u8, B)>)             // 240
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AttachmentView<T, B> {
+    pub filename: T,
+    pub mime_type: T,
+    /// Length of the whole content
+    pub size: u64,
+    pub sha256: [u8; consts::SHA256_LENGTH],
+    pub content: ContentView<B>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ContentView<B> {
+    Inline(B),                             // 0
+    Chunked { transfer: u64, count: u32 }  // 1
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ChunkView<B> {
+    pub transfer: u64,
+    pub index: u32,
+    pub data: B,
+}
+
+/// A UTF-8 string stored in `Bytes`.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SharedStr(Bytes);
+
+impl SharedStr {
+    pub fn as_bytes(&self) -> &Bytes {
+        &self.0
+    }
+}
+
+impl Deref for SharedStr {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        // Only ever built from valid UTF-8
+        unsafe { std::str::from_utf8_unchecked(&self.0) }
+    }
+}
+
+impl From<String> for SharedStr {
+    fn from(s: String) -> SharedStr {
+        SharedStr(Bytes::from(s))
+    }
+}
+
+impl From<SharedStr> for String {
+    fn from(s: SharedStr) -> String {
+        String::from(&*s)
+    }
+}
+
+impl fmt::Debug for SharedStr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl fmt::Display for SharedStr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<'a> MessageRef<'a> {
+    /// Decodes a message from the front of `buf`, advancing it past the consumed bytes.
+    pub fn decode(buf: &mut &'a [u8]) -> Result<MessageRef<'a>> {
+        MessageRef::decode_with(buf, &DecodeOptions::default())
+    }
+
+    /// Same as `decode`, but honours the given options.
+    pub fn decode_with(buf: &mut &'a [u8], opts: &DecodeOptions) -> Result<MessageRef<'a>> {
+        let outer = Nesting { depth: 0, start: buf.len() };
+        decode_nested_message(buf, opts, outer)
+    }
+}
+
+impl SharedMessage {
+    /// Decodes a message from the front of `buf`, advancing it past the consumed bytes.
+    pub fn decode(buf: &mut Bytes) -> Result<SharedMessage> {
+        SharedMessage::decode_with(buf, &DecodeOptions::default())
+    }
+
+    /// Same as `decode`, but honours the given options.
+    pub fn decode_with(buf: &mut Bytes, opts: &DecodeOptions) -> Result<SharedMessage> {
+        let outer = Nesting { depth: 0, start: buf.len() };
+        decode_nested_message(buf, opts, outer)
+    }
+}
+
+impl<T: Into<String>, B: Into<Vec<u8>>> MessageView<T, B> {
+    /// Copies the texts and bytes into an owned `Message`.
+    pub fn into_message(self) -> Message {
+        Message::from(self)
+    }
+}
+
+impl<T: Into<String>, B: Into<Vec<u8>>> From<MessageView<T, B>> for Message {
+    fn from(view: MessageView<T, B>) -> Message {
+        match view {
+            MessageView::Nop => Message::Nop,
+            MessageView::Control(ctrl) => Message::Control(ctrl),
+            MessageView::Text(s) => Message::Text(s.into()),
+            MessageView::RichText(s, spans) => Message::RichText(s.into(), spans),
+            MessageView::Emo(emo) => Message::Emo(emo.into()),
+            MessageView::Attachment(attachment) => Message::Attachment(attachment.into()),
+            MessageView::Chunk(chunk) => Message::Chunk(chunk.into()),
+            MessageView::Location(location) => Message::Location(location),
+            MessageView::Image(format, data) => Message::Image(format, data.into()),
+            MessageView::Voice { codec, duration, waveform, data } => Message::Voice {
+                codec,
+                duration,
+                waveform: waveform.map(Into::into),
+                data: data.into(),
+            },
+            MessageView::Compound(msgs) => {
+                Message::Compound(msgs.into_iter().map(Message::from).collect())
+            },
+            MessageView::Binary(data) => Message::Binary(data.into()),
+            MessageView::Extension { code, payload } => {
+                Message::Extension { code, payload: payload.into() }
+            },
+            MessageView::Object(obj) => Message::Object(obj),
+            MessageView::Unknown(code, payload) => Message::Unknown(code, payload.into()),
+        }
+    }
+}
+
+impl<T: Into<String>, B: Into<Vec<u8>>> From<EmoView<T, B>> for Emo {
+    fn from(view: EmoView<T, B>) -> Emo {
+        match view {
+            EmoView::Nop => Emo::Nop,
+            EmoView::Laugh => Emo::Laugh,
+            EmoView::Cry => Emo::Cry,
+            EmoView::Custom(id, image) => {
+                Emo::Custom(id.into(), image.map(|(format, data)| (format, data.into())))
+            }
+        }
+    }
+}
+
+impl<T: Into<String>, B: Into<Vec<u8>>> From<AttachmentView<T, B>> for Attachment {
+    fn from(view: AttachmentView<T, B>) -> Attachment {
+        Attachment {
+            filename: view.filename.into(),
+            mime_type: view.mime_type.into(),
+            size: view.size,
+            sha256: view.sha256,
+            content: view.content.into(),
+        }
+    }
+}
+
+impl<B: Into<Vec<u8>>> From<ContentView<B>> for Content {
+    fn from(view: ContentView<B>) -> Content {
+        match view {
+            ContentView::Inline(data) => Content::Inline(data.into()),
+            ContentView::Chunked { transfer, count } => Content::Chunked { transfer, count },
+        }
+    }
+}
+
+impl<B: Into<Vec<u8>>> From<ChunkView<B>> for Chunk {
+    fn from(view: ChunkView<B>) -> Chunk {
+        Chunk { transfer: view.transfer, index: view.index, data: view.data.into() }
+    }
+}
+
+impl<'a> Input for &'a [u8] {
+    type Text = Cow<'a, str>;
+    type Bytes = Cow<'a, [u8]>;
+
+    fn remaining(&self) -> &[u8] {
+        self
+    }
+
+    fn advance(&mut self, len: usize) {
+        *self = &self[len..];
+    }
+
+    fn split_to(&mut self, len: usize) -> &'a [u8] {
+        let (head, tail) = self.split_at(len);
+        *self = tail;
+        head
+    }
+
+    fn into_bytes(self) -> Cow<'a, [u8]> {
+        Cow::Borrowed(self)
+    }
+
+    fn joined(bytes: Vec<u8>) -> Cow<'a, [u8]> {
+        Cow::Owned(bytes)
+    }
+
+    fn text(bytes: Cow<'a, [u8]>, lossy_utf8: bool) -> Result<Cow<'a, str>> {
+        match bytes {
+            Cow::Borrowed(bytes) => match std::str::from_utf8(bytes) {
+                Ok(s) => Ok(Cow::Borrowed(s)),
+                Err(_) if lossy_utf8 => Ok(String::from_utf8_lossy(bytes)),
+                Err(e) => Err(Error::InvalidUtf8(e.valid_up_to()))
+            },
+            Cow::Owned(bytes) => match String::from_utf8(bytes) {
+                Ok(s) => Ok(Cow::Owned(s)),
+                Err(e) if lossy_utf8 => {
+                    Ok(Cow::Owned(String::from_utf8_lossy(e.as_bytes()).into_owned()))
+                },
+                Err(e) => Err(Error::InvalidUtf8(e.utf8_error().valid_up_to()))
+            }
+        }
+    }
+}
+
+impl Input for Bytes {
+    type Text = SharedStr;
+    type Bytes = Bytes;
+
+    fn remaining(&self) -> &[u8] {
+        self
+    }
+
+    fn advance(&mut self, len: usize) {
+        Buf::advance(self, len);
+    }
+
+    fn split_to(&mut self, len: usize) -> Bytes {
+        Bytes::split_to(self, len)
+    }
+
+    fn into_bytes(self) -> Bytes {
+        self
+    }
+
+    fn joined(bytes: Vec<u8>) -> Bytes {
+        Bytes::from(bytes)
+    }
+
+    fn text(bytes: Bytes, lossy_utf8: bool) -> Result<SharedStr> {
+        match std::str::from_utf8(&bytes) {
+            Ok(_) => Ok(SharedStr(bytes)),
+            Err(_) if lossy_utf8 => Ok(SharedStr::from(String::from_utf8_lossy(&bytes).into_owned())),
+            Err(e) => Err(Error::InvalidUtf8(e.valid_up_to()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::sync::Arc;
+    use bytes::{Bytes, BytesMut};
+    use proptest::prelude::*;
+    use super::super::consts;
+    use super::super::{Message, Emo};
+    use super::super::decoder::{Decoder, DecodeOptions, DecodeLimits};
+    use super::super::encoder::Encoder;
+    use super::super::decoder::Result;
+    use super::super::registry::{ExtensionType, Registry};
+    use super::{MessageView, MessageRef, SharedMessage, EmoView};
+
+    fn encode(msg: &Message) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        msg.encode_into(&mut buf);
+        buf.to_vec()
+    }
+
+    // Whether `part` points into `whole`
+    fn borrows(whole: &[u8], part: &[u8]) -> bool {
+        whole.as_ptr_range().contains(&part.as_ptr())
+    }
+
+    fn sample() -> Message {
+        Message::Compound(vec![
+            Message::Text(String::from("零拷贝")),
+            Message::Text("长".repeat(30000)),
+            Message::Emo(Emo::Custom(String::from("pack/cat"), Some((consts::MESSAGE_IMAGE_FORMAT_PNG, vec![7; 5])))),
+            Message::Unknown(200, vec![1, 2, 3]),
+        ])
+    }
+
+    #[test]
+    fn decode_borrowed() {
+        let encoded = encode(&sample());
+        let mut buf = &encoded[..];
+        let msg = MessageRef::decode(&mut buf).unwrap();
+        assert!(buf.is_empty());
+        match msg {
+            MessageView::Compound(ref msgs) => match &msgs[..] {
+                [MessageView::Text(Cow::Borrowed(short)),
+                 MessageView::Text(Cow::Owned(_)),
+                 MessageView::Emo(EmoView::Custom(Cow::Borrowed(id), Some((_, Cow::Borrowed(image))))),
+                 MessageView::Unknown(200, Cow::Borrowed(payload))] => {
+                    assert_eq!(*short, "零拷贝");
+                    assert!(borrows(&encoded, short.as_bytes()));
+                    assert!(borrows(&encoded, id.as_bytes()));
+                    assert!(borrows(&encoded, image));
+                    assert!(borrows(&encoded, payload));
+                },
+                other => panic!("unexpected messages: {:?}", other)
+            },
+            other => panic!("unexpected message: {:?}", other)
+        }
+        assert_eq!(msg.into_message(), sample());
+    }
+
+    #[test]
+    fn decode_shared() {
+        let encoded = Bytes::from(encode(&sample()));
+        let mut buf = encoded.clone();
+        let msg = SharedMessage::decode(&mut buf).unwrap();
+        assert!(buf.is_empty());
+        match msg {
+            MessageView::Compound(ref msgs) => match &msgs[..] {
+                [MessageView::Text(short), MessageView::Text(long), ..] => {
+                    assert_eq!(&**short, "零拷贝");
+                    assert!(borrows(&encoded, short.as_bytes()));
+                    assert!(!borrows(&encoded, long.as_bytes()));
+                },
+                other => panic!("unexpected messages: {:?}", other)
+            },
+            other => panic!("unexpected message: {:?}", other)
+        }
+        assert_eq!(msg.into_message(), sample());
+    }
+
+    #[test]
+    fn decode_registered_extension() {
+        let mut registry = Registry::new();
+        registry.register(142).unwrap();
+        let opts = DecodeOptions { registry: Some(Arc::new(registry)), ..DecodeOptions::default() };
+        let encoded = b"\x8e\x00\x00\x00\x02hi";
+        let mut buf = &encoded[..];
+        match MessageRef::decode_with(&mut buf, &opts).unwrap() {
+            MessageView::Extension { code: 142, payload: Cow::Borrowed(b"hi") } => {},
+            other => panic!("unexpected message: {:?}", other)
+        }
+        match MessageRef::decode(&mut &encoded[..]).unwrap() {
+            MessageView::Unknown(142, Cow::Borrowed(b"hi")) => {},
+            other => panic!("unexpected message: {:?}", other)
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Vote(u32);
+
+    impl Encoder for Vote {
+        fn encode_into(&self, buf: &mut BytesMut) {
+            self.0.encode_into(buf);
+        }
+    }
+
+    impl Decoder for Vote {
+        fn decode_with(buf: &mut BytesMut, opts: &DecodeOptions) -> Result<Self> {
+            Ok(Vote(u32::decode_with(buf, opts)?))
+        }
+    }
+
+    impl ExtensionType for Vote {
+        const CODE: u8 = 142;
+    }
+
+    #[test]
+    fn decode_registered_type() {
+        let mut registry = Registry::new();
+        registry.register_type::<Vote>().unwrap();
+        let opts = DecodeOptions { registry: Some(Arc::new(registry)), ..DecodeOptions::default() };
+        let msg = MessageRef::decode_with(&mut &b"\x8e\x00\x00\x00\x04\x00\x00\x00\x07"[..], &opts);
+        assert_eq!(msg.unwrap().into_message(), Message::Object(Box::new(Vote(7))));
+        let mut buf = Bytes::from_static(b"\x8e\x00\x00\x00\x02\x00\x00");
+        match SharedMessage::decode_with(&mut buf, &opts) {
+            Err(e) => assert_eq!(e.to_string(),
+                                 "type 142 at offset 5: unexpected end of input, 2 more bytes needed"),
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    // Decodes `bytes` into a `MessageRef`, a `SharedMessage` and a `Message`, which must agree.
+    fn check_same_as_message(bytes: &[u8], opts: &DecodeOptions) -> std::result::Result<(), TestCaseError> {
+        let mut buf = BytesMut::from(bytes);
+        let expected = Message::decode_with(&mut buf, opts).map_err(|e| e.to_string());
+        let mut borrowed = bytes;
+        let decoded = MessageRef::decode_with(&mut borrowed, opts)
+            .map(MessageRef::into_message)
+            .map_err(|e| e.to_string());
+        prop_assert_eq!(&decoded, &expected);
+        let mut shared = Bytes::from(bytes.to_vec());
+        let decoded = SharedMessage::decode_with(&mut shared, opts)
+            .map(SharedMessage::into_message)
+            .map_err(|e| e.to_string());
+        prop_assert_eq!(&decoded, &expected);
+        if expected.is_ok() {
+            prop_assert_eq!(borrowed.len(), buf.len());
+            prop_assert_eq!(shared.len(), buf.len());
+        }
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn decode_like_message(msg in any::<Message>(), pos in any::<usize>(), byte in any::<u8>(),
+                               mangle in 0..3) {
+            let mut bytes = encode(&msg);
+            let pos = pos % bytes.len();
+            match mangle {
+                0 => {},
+                1 => bytes[pos] = byte,
+                _ => bytes.truncate(pos)
+            }
+            check_same_as_message(&bytes, &DecodeOptions::default())?;
+            let opts = DecodeOptions {
+                lossy_utf8: true,
+                limits: DecodeLimits { max_depth: 2, max_children: 300, ..DecodeLimits::default() },
+                ..DecodeOptions::default()
+            };
+            check_same_as_message(&bytes, &opts)?;
+        }
+
+        #[test]
+        fn decode_arbitrary_bytes(bytes in proptest::collection::vec(any::<u8>(), 0..256)) {
+            check_same_as_message(&bytes, &DecodeOptions::default())?;
+        }
+    }
+}